@@ -1,6 +1,14 @@
 fn main() {
+    // Sources of shairplay's playfair are needed only for built-in FairPlay
+    if std::env::var_os("CARGO_FEATURE_FAIRPLAY").is_none() {
+        return;
+    }
+
+    println!("cargo:rerun-if-env-changed=FAIRPLAY3_SRC");
+    let src = std::env::var("FAIRPLAY3_SRC").expect("FAIRPLAY3_SRC must be set for `fairplay`");
+
     let mut build = cc::Build::new();
-    for entry in glob::glob(&format!("{}/*.c", env!("FAIRPLAY3_SRC"))).unwrap() {
+    for entry in glob::glob(&format!("{src}/*.c")).unwrap() {
         build.file(entry.unwrap());
     }
     build.cargo_warnings(false).compile("fairplay3");
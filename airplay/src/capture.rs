@@ -0,0 +1,253 @@
+//! Offline decryption of captured sessions, so issues can be analysed without a live sender.
+//!
+//! Keys are the ones exported with [`KeyLog`](crate::config::KeyLog). Parsing of the capture is
+//! up to the caller: decoders are fed with reassembled TCP payloads, realtime audio is decrypted
+//! datagram by datagram.
+
+use std::io;
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::Decoder;
+
+use crate::{
+    crypto::{AesIv128, AesKey128, ChaCha20Poly1305Key, Secret},
+    pairing::{SessionKey, homekit::codec::HAPDecoder},
+    playback::{audio::AudioPacket, video::VideoPacket},
+    streaming::{
+        Encryption, EncryptionMaterial, build_audio_cipher, build_video_cipher, crypto, header,
+        video_packet_kind,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Requests, sent to the receiver
+    FromSender,
+    /// Responses, sent to the sender
+    FromReceiver,
+}
+
+/// Keys of a single stream, as they're passed on its `SETUP`.
+#[derive(Debug, Clone, Default)]
+pub struct StreamKeys {
+    /// `SESSION_KEY` of the RTSP connection
+    pub session_key: Option<Secret<Vec<u8>>>,
+    /// `AES_KEY` of the RTSP connection
+    pub aes_key: Option<AesKey128>,
+    pub aes_iv: Option<AesIv128>,
+    /// Mode of `AES_KEY`
+    pub aes_gcm: bool,
+    /// `shk` of the stream
+    pub shared_key: Option<ChaCha20Poly1305Key>,
+    pub stream_connection_id: Option<u64>,
+}
+
+impl StreamKeys {
+    /// Same choice of a cipher as on `SETUP`, the stream is taken as unencrypted if keys don't
+    /// fit any cipher.
+    fn encryption(self) -> Encryption {
+        Encryption::try_from(EncryptionMaterial {
+            stream_connection_id: self.stream_connection_id,
+            chacha_key: self.shared_key,
+            session_key: self.session_key.map(|key_material| SessionKey {
+                key_material,
+                upgrade_channel: false,
+                pairing_id: None,
+            }),
+            aeskey: self.aes_key,
+            aesiv: self.aes_iv,
+            aes_gcm: self.aes_gcm,
+            allow_unencrypted: false,
+        })
+        .unwrap_or_else(|err| {
+            tracing::warn!(%err, "no keys for the stream, it's taken as unencrypted");
+            Encryption::None
+        })
+    }
+}
+
+/// HAP framing of the RTSP connection after pair-verify, one per direction.
+pub struct ControlDecoder {
+    inner: HAPDecoder,
+}
+
+impl ControlDecoder {
+    pub fn new(key_material: &[u8], direction: Direction) -> Self {
+        const SALT: &[u8] = b"Control-Salt";
+
+        let info: &[u8] = match direction {
+            Direction::FromSender => b"Control-Write-Encryption-Key",
+            Direction::FromReceiver => b"Control-Read-Encryption-Key",
+        };
+
+        Self {
+            inner: HAPDecoder::with_salt_and_info(key_material, SALT, info),
+        }
+    }
+}
+
+impl Decoder for ControlDecoder {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.inner.decode(src)
+    }
+}
+
+pub struct AudioDecryptor {
+    cipher: Box<dyn crypto::AudioCipher + Send + Sync>,
+}
+
+impl AudioDecryptor {
+    pub fn new(keys: StreamKeys) -> Self {
+        Self {
+            cipher: build_audio_cipher(&keys.encryption(), true),
+        }
+    }
+
+    /// Decrypts a datagram of realtime audio in place, replayed ones are rejected.
+    pub fn decrypt(&mut self, rtp: &mut BytesMut) -> io::Result<()> {
+        if rtp.len() < AudioPacket::HEADER_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                crypto::DecryptError::Malformed,
+            ));
+        }
+
+        self.cipher
+            .decrypt(rtp)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+/// Buffered audio, i.e. RTP packets prefixed with their length.
+pub struct BufferedAudioDecoder {
+    cipher: Box<dyn crypto::AudioCipher + Send + Sync>,
+    trailer_len: usize,
+}
+
+impl BufferedAudioDecoder {
+    pub fn new(keys: StreamKeys) -> Self {
+        let encryption = keys.encryption();
+        Self {
+            cipher: build_audio_cipher(&encryption, false),
+            trailer_len: encryption.audio_trailer_len(),
+        }
+    }
+}
+
+impl Decoder for BufferedAudioDecoder {
+    type Item = AudioPacket;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < 2 {
+            return Ok(None);
+        }
+
+        // 2 is pkt_len field size itself
+        let pkt_len = usize::from(u16::from_be_bytes([src[0], src[1]])).saturating_sub(2);
+        if pkt_len < AudioPacket::HEADER_LEN + self.trailer_len {
+            return Err(io::Error::other("malformed buffered stream"));
+        }
+        if src.len() < 2 + pkt_len {
+            src.reserve(2 + pkt_len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(2);
+        let mut rtp = src.split_to(pkt_len);
+        if let Err(err) = self.cipher.decrypt(&mut rtp) {
+            tracing::warn!(%err, "buffered audio packet");
+        }
+
+        Ok(Some(AudioPacket { rtp }))
+    }
+}
+
+/// Mirroring stream, heartbeats are passed as well.
+pub struct VideoDecoder {
+    cipher: Box<dyn crypto::VideoCipher + Send + Sync>,
+}
+
+impl VideoDecoder {
+    pub fn new(keys: StreamKeys) -> Self {
+        Self {
+            cipher: build_video_cipher(&keys.encryption()),
+        }
+    }
+}
+
+impl Decoder for VideoDecoder {
+    type Item = VideoPacket;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        const LEN: usize = header::VideoHeader::LEN;
+
+        let Some(header) = src.first_chunk::<LEN>().copied() else {
+            return Ok(None);
+        };
+        let parsed = header::VideoHeader::parse(&header);
+        let need = LEN + parsed.payload_len as usize;
+        if src.len() < need {
+            src.reserve(need - src.len());
+            return Ok(None);
+        }
+
+        src.advance(LEN);
+        let mut payload = src.split_to(parsed.payload_len as usize);
+        let kind = video_packet_kind(&parsed, &payload);
+        if parsed.kind.is_encrypted() && self.cipher.decrypt(header, &mut payload).is_err() {
+            tracing::warn!(?kind, "video packet decryption failed");
+        }
+
+        Ok(Some(VideoPacket {
+            kind,
+            timestamp: parsed.ntp_timestamp,
+            keyframe: parsed.flags.is_keyframe(),
+            payload,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::{BufferedAudioDecoder, ControlDecoder, Direction, StreamKeys};
+    use crate::{pairing::homekit::codec::HAPEncoder, playback::audio::AudioPacket};
+
+    const SHARED_SECRET: &[u8] = b"01234567890123456789012345678901";
+
+    #[test]
+    fn receiver_direction() {
+        const RESPONSE: &[u8] = b"RTSP/1.0 200 OK\r\nCSeq: 1\r\n\r\n";
+
+        let mut encoded = BytesMut::new();
+        HAPEncoder::new(SHARED_SECRET)
+            .encode(RESPONSE, &mut encoded)
+            .unwrap();
+
+        let mut decoder = ControlDecoder::new(SHARED_SECRET, Direction::FromReceiver);
+        let decoded = decoder.decode(&mut encoded).unwrap();
+
+        assert_eq!(decoded.as_deref(), Some(RESPONSE));
+    }
+
+    #[test]
+    fn unencrypted_buffered_audio() {
+        let rtp = [0x80u8; AudioPacket::HEADER_LEN + 4];
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&(rtp.len() as u16 + 2).to_be_bytes());
+        src.extend_from_slice(&rtp);
+
+        let mut decoder = BufferedAudioDecoder::new(StreamKeys::default());
+        let pkt = decoder.decode(&mut src).unwrap().expect("whole packet");
+
+        assert_eq!(&pkt.rtp[..], &rtp[..]);
+        assert!(src.is_empty());
+    }
+}
@@ -0,0 +1,285 @@
+use std::{net::IpAddr, str::FromStr};
+
+use http::StatusCode;
+use macaddr::MacAddr6;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CidrError {
+    #[error("invalid address: {0}")]
+    Addr(#[from] std::net::AddrParseError),
+    #[error("invalid prefix length")]
+    PrefixLen,
+}
+
+#[derive(Debug, Error, Copy, Clone, PartialEq, Eq)]
+pub enum AccessDenied {
+    #[error("sender is forbidden by access policy")]
+    Forbidden,
+    #[error("only paired senders allowed")]
+    NotPaired,
+}
+
+impl From<AccessDenied> for StatusCode {
+    fn from(value: AccessDenied) -> Self {
+        match value {
+            AccessDenied::Forbidden => StatusCode::FORBIDDEN,
+            AccessDenied::NotPaired => crate::rtsp::connection_authorization_required(),
+        }
+    }
+}
+
+/// Network in CIDR notation, e.g. `192.168.1.0/24` or `fd00::/8`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self, CidrError> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_len {
+            return Err(CidrError::PrefixLen);
+        }
+
+        Ok(Self { addr, prefix_len })
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len));
+                let mask = mask.unwrap_or_default();
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len));
+                let mask = mask.unwrap_or_default();
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('/') {
+            Some((addr, prefix_len)) => Self::new(
+                addr.parse()?,
+                prefix_len.parse().map_err(|_| CidrError::PrefixLen)?,
+            ),
+            None => {
+                let addr: IpAddr = s.parse()?;
+                Self::new(addr, if addr.is_ipv4() { 32 } else { 128 })
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rule {
+    Cidr(Cidr),
+    /// `deviceID` of sender's `SETUP`, compared case-insensitively
+    DeviceId(String),
+    /// `macAddress` of sender's `SETUP`
+    MacAddr(MacAddr6),
+    /// Pairing identifier of HomeKit controller
+    PairingId(Vec<u8>),
+}
+
+/// What is known about the sender at the moment of the check.
+#[derive(Debug, Copy, Clone)]
+pub struct Peer<'a> {
+    pub addr: IpAddr,
+    pub device_id: Option<&'a str>,
+    pub mac_addr: Option<MacAddr6>,
+    pub pairing_id: Option<&'a [u8]>,
+}
+
+impl Peer<'_> {
+    pub fn new(addr: IpAddr) -> Self {
+        Self {
+            addr,
+            device_id: None,
+            mac_addr: None,
+            pairing_id: None,
+        }
+    }
+}
+
+impl Rule {
+    /// `None` if the sender's attribute isn't known yet.
+    fn matches(&self, peer: &Peer<'_>) -> Option<bool> {
+        match self {
+            Self::Cidr(cidr) => Some(cidr.contains(peer.addr)),
+            Self::DeviceId(id) => peer.device_id.map(|x| x.eq_ignore_ascii_case(id)),
+            Self::MacAddr(mac_addr) => peer.mac_addr.map(|x| x == *mac_addr),
+            Self::PairingId(id) => peer.pairing_id.map(|x| x == id.as_slice()),
+        }
+    }
+}
+
+/// Restricts senders which may pair and stream.
+///
+/// The policy is evaluated several times: at pairing only the address and pairing identifier are
+/// known, while `SETUP` also brings sender's device id and MAC. Rules on unknown attributes are
+/// skipped until the attributes become known, but a stream is set up only if an allow rule
+/// actually matches.
+#[derive(Debug, Clone, Default)]
+pub struct AccessPolicy {
+    /// If not empty, sender must match one of the rules
+    pub allow: Vec<Rule>,
+    /// Sender matching any of the rules is rejected, takes precedence over [`Self::allow`]
+    pub deny: Vec<Rule>,
+    /// Only HomeKit controllers passed pair-verify may stream, i.e. saved into keychain
+    pub only_paired: bool,
+}
+
+impl AccessPolicy {
+    /// Checks the sender before pairing is done, [`Self::only_paired`] isn't applied.
+    pub fn check_pairing(&self, peer: &Peer<'_>) -> Result<(), AccessDenied> {
+        if self
+            .deny
+            .iter()
+            .any(|rule| rule.matches(peer) == Some(true))
+        {
+            return Err(AccessDenied::Forbidden);
+        }
+
+        let mut allow = self.allow.iter().map(|rule| rule.matches(peer));
+        if !self.allow.is_empty() && allow.all(|matches| matches == Some(false)) {
+            return Err(AccessDenied::Forbidden);
+        }
+
+        Ok(())
+    }
+
+    /// Checks the sender before a stream is set up.
+    pub fn check(&self, peer: &Peer<'_>) -> Result<(), AccessDenied> {
+        self.check_pairing(peer)?;
+        if self.only_paired && peer.pairing_id.is_none() {
+            return Err(AccessDenied::NotPaired);
+        }
+        if !self.allow.is_empty()
+            && !self
+                .allow
+                .iter()
+                .any(|rule| rule.matches(peer) == Some(true))
+        {
+            return Err(AccessDenied::Forbidden);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use macaddr::MacAddr6;
+
+    use super::{AccessDenied, AccessPolicy, Cidr, Peer, Rule};
+
+    #[test]
+    fn cidr() {
+        let net: Cidr = "192.168.1.0/24".parse().unwrap();
+        assert!(net.contains("192.168.1.42".parse().unwrap()));
+        assert!(net.contains("::ffff:192.168.1.42".parse().unwrap()));
+        assert!(!net.contains("192.168.2.1".parse().unwrap()));
+        assert!(!net.contains("fd00::1".parse().unwrap()));
+
+        let net: Cidr = "fd00::/8".parse().unwrap();
+        assert!(net.contains("fd12::1".parse().unwrap()));
+        assert!(!net.contains("fe80::1".parse().unwrap()));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("8.8.8.8".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn deny_takes_precedence() {
+        let policy = AccessPolicy {
+            allow: vec![Rule::Cidr("10.0.0.0/8".parse().unwrap())],
+            deny: vec![Rule::DeviceId("AA:BB:CC:DD:EE:FF".into())],
+            only_paired: false,
+        };
+        let addr: IpAddr = "10.1.2.3".parse().unwrap();
+
+        assert_eq!(policy.check(&Peer::new(addr)), Ok(()));
+        assert_eq!(
+            policy.check(&Peer {
+                device_id: Some("aa:bb:cc:dd:ee:ff"),
+                ..Peer::new(addr)
+            }),
+            Err(AccessDenied::Forbidden)
+        );
+        assert_eq!(
+            policy.check(&Peer::new("172.16.0.1".parse().unwrap())),
+            Err(AccessDenied::Forbidden)
+        );
+    }
+
+    #[test]
+    fn unknown_attributes_are_skipped() {
+        let mac_addr = MacAddr6::new(1, 2, 3, 4, 5, 6);
+        let policy = AccessPolicy {
+            allow: vec![Rule::MacAddr(mac_addr)],
+            deny: vec![],
+            only_paired: true,
+        };
+        let peer = Peer::new("10.1.2.3".parse().unwrap());
+
+        assert_eq!(policy.check_pairing(&peer), Ok(()));
+        assert_eq!(policy.check(&peer), Err(AccessDenied::NotPaired));
+        assert_eq!(
+            policy.check(&Peer {
+                mac_addr: Some(MacAddr6::nil()),
+                pairing_id: Some(b"id"),
+                ..peer
+            }),
+            Err(AccessDenied::Forbidden)
+        );
+        assert_eq!(
+            policy.check(&Peer {
+                mac_addr: Some(mac_addr),
+                pairing_id: Some(b"id"),
+                ..peer
+            }),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn unknown_attributes_deny_streams() {
+        let policy = AccessPolicy {
+            allow: vec![
+                Rule::DeviceId("AA:BB:CC:DD:EE:FF".into()),
+                Rule::MacAddr(MacAddr6::new(1, 2, 3, 4, 5, 6)),
+            ],
+            deny: vec![],
+            only_paired: false,
+        };
+        // Streams-only `SETUP` of a sender which never introduced itself
+        let peer = Peer::new("10.1.2.3".parse().unwrap());
+
+        assert_eq!(policy.check_pairing(&peer), Ok(()));
+        assert_eq!(policy.check(&peer), Err(AccessDenied::Forbidden));
+        assert_eq!(
+            policy.check(&Peer {
+                device_id: Some("aa:bb:cc:dd:ee:ff"),
+                ..peer
+            }),
+            Ok(())
+        );
+    }
+}
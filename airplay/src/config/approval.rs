@@ -0,0 +1,30 @@
+use std::net::SocketAddr;
+
+use futures::future::BoxFuture;
+
+/// Controller which finished pair-setup and wants to be saved into keychain.
+#[derive(Debug, Clone)]
+pub struct PairingRequest {
+    /// Pairing identifier of the controller, usually UUID string
+    pub identifier: Vec<u8>,
+    /// Ed25519 long-term public key of the controller
+    pub public_key: Vec<u8>,
+    pub remote_addr: SocketAddr,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Approval {
+    Accept,
+    /// Controller is rejected, it may try again later
+    Deny,
+    /// Pairing of new controllers isn't possible right now, e.g. nobody's there to approve
+    Unavailable,
+}
+
+/// Decides whether a new controller may be trusted, e.g. by asking an admin from another app.
+///
+/// Called at M5 of HomeKit pair-setup before [`super::Keychain::trust`], the controller
+/// waits for the response until it times out itself.
+pub trait PairingApproval: Send + Sync + 'static {
+    fn approve(&self, request: PairingRequest) -> BoxFuture<'static, Approval>;
+}
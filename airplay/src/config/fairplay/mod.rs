@@ -0,0 +1,46 @@
+use std::sync::Arc;
+
+use thiserror::Error;
+
+#[cfg(feature = "fairplay")]
+pub use self::shairplay::ShairplayFairPlay;
+use crate::crypto::AesKey128;
+
+#[cfg(feature = "fairplay")]
+mod shairplay;
+
+#[derive(Debug, Error)]
+pub enum FairPlayError {
+    #[error("insufficient data")]
+    InsufficientData,
+    #[error("invalid version: {0}")]
+    InvalidVersion(u8),
+    #[error("invalid msg type: {0}")]
+    InvalidMsgType(u8),
+    #[error("invalid mode: {0}")]
+    InvalidMode(u8),
+    #[error("invalid seq: {0}")]
+    InvalidSeq(u8),
+    #[error("invalid length: {0}")]
+    InvalidLength(usize),
+}
+
+/// FairPlay (v3) used by senders to wrap the AES key of legacy streams.
+pub trait FairPlayProvider: Send + Sync + 'static {
+    /// Answers a message of `/fp-setup` handshake
+    fn decode_setup(&self, request: &[u8]) -> Result<Vec<u8>, FairPlayError>;
+
+    /// Unwraps `ekey` with the last message of the handshake
+    fn decrypt_key(&self, last_msg: &[u8], ekey: &[u8]) -> Result<AesKey128, FairPlayError>;
+}
+
+/// Built-in provider if `fairplay` feature is enabled.
+#[cfg(feature = "fairplay")]
+pub(crate) fn builtin() -> Option<Arc<dyn FairPlayProvider>> {
+    Some(Arc::new(ShairplayFairPlay))
+}
+
+#[cfg(not(feature = "fairplay"))]
+pub(crate) fn builtin() -> Option<Arc<dyn FairPlayProvider>> {
+    None
+}
@@ -3,8 +3,7 @@
 //!
 //! Thank you, my dear!
 
-use thiserror::Error;
-
+use super::{FairPlayError, FairPlayProvider};
 use crate::crypto::AesKey128;
 
 const MESSAGES: [&[u8]; 4] = [
@@ -48,36 +47,46 @@ const MESSAGES: [&[u8]; 4] = [
     ],
 ];
 const FP_HEADER: &[u8] = &[70, 80, 76, 89, 3, 1, 4, 0, 0, 0, 0, 20];
+const MSG_LEN: usize = 164;
+const EKEY_LEN: usize = 72;
+
+/// Built-in provider compiled from shairplay's `playfair` sources.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ShairplayFairPlay;
+
+impl FairPlayProvider for ShairplayFairPlay {
+    fn decode_setup(&self, request: &[u8]) -> Result<Vec<u8>, FairPlayError> {
+        decode_buf(request)
+    }
 
-#[derive(Debug, Error)]
-pub enum DecodingError {
-    #[error("insufficient data")]
-    InsufficientData,
-    #[error("invalid version: {0}")]
-    InvalidVersion(u8),
-    #[error("invalid msg type: {0}")]
-    InvalidMsgType(u8),
-    #[error("invalid mode: {0}")]
-    InvalidMode(u8),
-    #[error("invalid seq: {0}")]
-    InvalidSeq(u8),
+    fn decrypt_key(&self, last_msg: &[u8], ekey: &[u8]) -> Result<AesKey128, FairPlayError> {
+        // playfair reads both buffers without any checks
+        if last_msg.len() != MSG_LEN {
+            return Err(FairPlayError::InvalidLength(last_msg.len()));
+        }
+        if ekey.len() != EKEY_LEN {
+            return Err(FairPlayError::InvalidLength(ekey.len()));
+        }
+
+        Ok(decrypt_key(last_msg, ekey))
+    }
 }
 
-pub fn decode_buf(buf: impl AsRef<[u8]>) -> Result<Vec<u8>, DecodingError> {
+fn decode_buf(buf: impl AsRef<[u8]>) -> Result<Vec<u8>, FairPlayError> {
     let buf = buf.as_ref();
 
     match buf.get(4) {
         Some(3) => {}
-        Some(version) => return Err(DecodingError::InvalidVersion(*version)),
-        None => return Err(DecodingError::InsufficientData),
+        Some(version) => return Err(FairPlayError::InvalidVersion(*version)),
+        None => return Err(FairPlayError::InsufficientData),
     }
 
     match buf.get(5) {
         Some(1) => match buf.get(6) {
             Some(1) => match buf.get(14) {
                 Some(mode @ 0..=4) => Ok(MESSAGES[*mode as usize].to_vec()),
-                Some(mode) => Err(DecodingError::InvalidMode(*mode)),
-                None => Err(DecodingError::InsufficientData),
+                Some(mode) => Err(FairPlayError::InvalidMode(*mode)),
+                None => Err(FairPlayError::InsufficientData),
             },
             Some(3) => {
                 let mut output = vec![0; FP_HEADER.len() + 20];
@@ -87,18 +96,18 @@ pub fn decode_buf(buf: impl AsRef<[u8]>) -> Result<Vec<u8>, DecodingError> {
                         output[FP_HEADER.len()..].copy_from_slice(suffix);
                         Ok(output)
                     }
-                    None => Err(DecodingError::InsufficientData),
+                    None => Err(FairPlayError::InsufficientData),
                 }
             }
-            Some(seq) => Err(DecodingError::InvalidSeq(*seq)),
-            None => Err(DecodingError::InsufficientData),
+            Some(seq) => Err(FairPlayError::InvalidSeq(*seq)),
+            None => Err(FairPlayError::InsufficientData),
         },
-        Some(msg_type) => Err(DecodingError::InvalidMsgType(*msg_type)),
-        None => Err(DecodingError::InsufficientData),
+        Some(msg_type) => Err(FairPlayError::InvalidMsgType(*msg_type)),
+        None => Err(FairPlayError::InsufficientData),
     }
 }
 
-pub fn decrypt_key(message: impl AsRef<[u8]>, encrypted_aes_key: impl AsRef<[u8]>) -> AesKey128 {
+fn decrypt_key(message: impl AsRef<[u8]>, encrypted_aes_key: impl AsRef<[u8]>) -> AesKey128 {
     unsafe extern "C" {
         fn playfair_decrypt(msg: *const u8, cipher_text: *const u8, out: *mut u8);
     }
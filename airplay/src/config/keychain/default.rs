@@ -1,8 +1,16 @@
-use std::{collections::HashMap, sync::Mutex};
+use std::{
+    collections::HashMap,
+    fmt::{self, Write},
+    sync::Mutex,
+};
 
 use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use macaddr::MacAddr6;
+use rand::Rng;
+use zeroize::Zeroize;
 
 use super::Keychain;
+use crate::crypto::{Secret, hkdf};
 
 // TODO : I don't like mixing in-memory keychain algorithm and crypto algorith, but whatever
 pub struct DefaultKeychain {
@@ -12,6 +20,8 @@ pub struct DefaultKeychain {
     limit: usize,
 }
 
+/// The same identity for every instance, senders confuse receivers on the same network.
+/// Use [`DefaultKeychain::random`] or [`DefaultKeychain::from_seed`] instead.
 impl Default for DefaultKeychain {
     fn default() -> Self {
         let signing_key = SigningKey::from_bytes(&[5; 32]);
@@ -25,6 +35,76 @@ impl Default for DefaultKeychain {
     }
 }
 
+impl DefaultKeychain {
+    /// New identity on every start, senders will have to pair again after restart.
+    pub fn random(mut rng: impl Rng) -> Self {
+        let mut seed = Secret::new([0u8; 32]);
+        rng.fill_bytes(&mut *seed);
+
+        Self::from_seed(*seed)
+    }
+
+    /// The same seed always gives the same key and identifier.
+    pub fn from_seed(mut seed: [u8; 32]) -> Self {
+        let signing_key = SigningKey::from_bytes(&seed);
+        let verifying_key = signing_key.verifying_key();
+        let self_id = derive_id(&seed);
+        seed.zeroize();
+
+        Self {
+            self_id,
+            keypair: (signing_key, verifying_key),
+            trusted: Mutex::default(),
+            limit: 10,
+        }
+    }
+
+    /// Replaces the identifier with one derived from the MAC, the key is left as is.
+    /// Senders remember the key by the identifier, so it has to be persisted with the seed.
+    pub fn with_mac_id(mut self, mac_addr: MacAddr6) -> Self {
+        self.self_id = derive_id(mac_addr.as_bytes());
+        self
+    }
+
+    /// Pairing identifier, advertised as `pi`.
+    pub fn pairing_id(&self) -> &str {
+        std::str::from_utf8(&self.self_id).expect("identifier is always valid UTF-8")
+    }
+}
+
+/// Only the identifier is printed, the signing key zeroizes itself on drop.
+impl fmt::Debug for DefaultKeychain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DefaultKeychain")
+            .field("id", &self.pairing_id())
+            .finish_non_exhaustive()
+    }
+}
+
+fn derive_id(input: &[u8]) -> Vec<u8> {
+    let id = hkdf(
+        input,
+        b"Pairing-Identifier-Salt",
+        b"Pairing-Identifier-Info",
+    );
+    uuid(id[..16].try_into().unwrap()).into_bytes()
+}
+
+/// Formats bytes as random (version 4) UUID.
+fn uuid(mut bytes: [u8; 16]) -> String {
+    bytes[6] = (bytes[6] & 0x0F) | 0x40;
+    bytes[8] = (bytes[8] & 0x3F) | 0x80;
+
+    let mut s = String::with_capacity(36);
+    for (i, byte) in bytes.iter().enumerate() {
+        if matches!(i, 4 | 6 | 8 | 10) {
+            s.push('-');
+        }
+        let _ = write!(s, "{byte:02X}");
+    }
+    s
+}
+
 impl Keychain for DefaultKeychain {
     fn id(&self) -> &[u8] {
         &self.self_id
@@ -67,3 +147,48 @@ impl Keychain for DefaultKeychain {
         key.verify_strict(message, &signature).is_ok()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use macaddr::MacAddr6;
+
+    use super::{DefaultKeychain, Keychain};
+
+    #[test]
+    fn deterministic_identity() {
+        let first = DefaultKeychain::from_seed([7; 32]);
+        let second = DefaultKeychain::from_seed([7; 32]);
+        assert_eq!(first.id(), second.id());
+        assert_eq!(first.pubkey(), second.pubkey());
+
+        let other = DefaultKeychain::from_seed([8; 32]);
+        assert_ne!(first.id(), other.id());
+        assert_ne!(first.pubkey(), other.pubkey());
+    }
+
+    #[test]
+    fn mac_gives_only_identifier() {
+        let mac_addr = MacAddr6::new(0x02, 0x11, 0x22, 0x33, 0x44, 0x55);
+        let first = DefaultKeychain::from_seed([7; 32]).with_mac_id(mac_addr);
+        let second = DefaultKeychain::from_seed([8; 32]).with_mac_id(mac_addr);
+        assert_eq!(first.id(), second.id());
+        assert_ne!(first.pubkey(), second.pubkey());
+
+        let other = DefaultKeychain::from_seed([7; 32]).with_mac_id(MacAddr6::nil());
+        assert_ne!(first.id(), other.id());
+        assert_eq!(first.pubkey(), other.pubkey());
+    }
+
+    #[test]
+    fn uuid_identifier() {
+        let keychain = DefaultKeychain::random(rand::rng());
+        let id = keychain.pairing_id();
+
+        assert_eq!(id.len(), 36);
+        assert_eq!(id.as_bytes()[14], b'4');
+        assert_eq!(
+            id.split('-').map(str::len).collect::<Vec<_>>(),
+            [8, 4, 4, 4, 12]
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Export of keys for offline decryption of captures, in the spirit of `SSLKEYLOGFILE`.
+//!
+//! Every entry is a single line of space-separated fields, binary values are lowercase hex and
+//! missing ones are `-`. The first three fields are the label, receiver's and sender's addresses
+//! of the RTSP connection:
+//!
+//! ```text
+//! SESSION_KEY <local_addr> <remote_addr> <key_material>
+//! AES_KEY <local_addr> <remote_addr> <key> <iv> <mode>
+//! STREAM <local_addr> <remote_addr> <type> <stream_connection_id> <shk>
+//! ```
+//!
+//! `SESSION_KEY` is a shared secret of pair-verify or transient pair-setup, the RTSP channel and
+//! HomeKit streams derive their keys from it. `AES_KEY` is FairPlay's key, already hashed with
+//! the session key if the sender is paired, its mode is `gcm` for FairPlay SAP v2.5 or `cbc`
+//! otherwise, where video uses CTR. `STREAM` is written on every `SETUP` of a stream with
+//! its numeric type, e.g. 96 for realtime audio or 110 for video.
+
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{self, Write as _},
+    net::SocketAddr,
+    path::Path,
+    sync::Mutex,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum KeyLogEntry<'a> {
+    SessionKey {
+        key_material: &'a [u8],
+    },
+    AesKey {
+        key: &'a [u8],
+        iv: &'a [u8],
+        gcm: bool,
+    },
+    Stream {
+        stream_type: u32,
+        stream_connection_id: Option<u64>,
+        shared_key: Option<&'a [u8]>,
+    },
+}
+
+impl KeyLogEntry<'_> {
+    /// Line of the documented format, without the trailing newline.
+    pub fn to_line(&self, local_addr: SocketAddr, remote_addr: SocketAddr) -> String {
+        let mut line = String::new();
+        let _ = match self {
+            Self::SessionKey { key_material } => write!(
+                line,
+                "SESSION_KEY {local_addr} {remote_addr} {}",
+                hex::encode(key_material)
+            ),
+            Self::AesKey { key, iv, gcm } => write!(
+                line,
+                "AES_KEY {local_addr} {remote_addr} {} {} {}",
+                hex::encode(key),
+                hex::encode(iv),
+                if *gcm { "gcm" } else { "cbc" }
+            ),
+            Self::Stream {
+                stream_type,
+                stream_connection_id,
+                shared_key,
+            } => write!(
+                line,
+                "STREAM {local_addr} {remote_addr} {stream_type} {} {}",
+                stream_connection_id.map_or_else(|| "-".to_string(), |id| id.to_string()),
+                shared_key.map_or_else(|| "-".to_string(), hex::encode)
+            ),
+        };
+
+        line
+    }
+}
+
+/// Sink of keys, called each time a key is established. Never set it in production.
+pub trait KeyLog: Send + Sync + 'static {
+    fn log(&self, local_addr: SocketAddr, remote_addr: SocketAddr, entry: KeyLogEntry<'_>);
+}
+
+/// Appends entries to a file, shared by all connections.
+#[derive(Debug)]
+pub struct KeyLogFile {
+    file: Mutex<File>,
+}
+
+impl KeyLogFile {
+    /// New file is readable by the owner only on unix, permissions of an existing one are kept.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options.open(path)?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl KeyLog for KeyLogFile {
+    fn log(&self, local_addr: SocketAddr, remote_addr: SocketAddr, entry: KeyLogEntry<'_>) {
+        let line = entry.to_line(local_addr, remote_addr);
+        if let Err(err) = writeln!(self.file.lock().unwrap(), "{line}") {
+            tracing::warn!(%err, "key log entry isn't written");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{KeyLogEntry, KeyLogFile};
+
+    #[test]
+    fn line_format() {
+        let local_addr: SocketAddr = "192.168.1.2:7000".parse().unwrap();
+        let remote_addr: SocketAddr = "192.168.1.3:52311".parse().unwrap();
+
+        assert_eq!(
+            KeyLogEntry::SessionKey {
+                key_material: &[0xde, 0xad]
+            }
+            .to_line(local_addr, remote_addr),
+            "SESSION_KEY 192.168.1.2:7000 192.168.1.3:52311 dead"
+        );
+        assert_eq!(
+            KeyLogEntry::AesKey {
+                key: &[1, 2],
+                iv: &[0xff],
+                gcm: true,
+            }
+            .to_line(local_addr, remote_addr),
+            "AES_KEY 192.168.1.2:7000 192.168.1.3:52311 0102 ff gcm"
+        );
+        assert_eq!(
+            KeyLogEntry::Stream {
+                stream_type: 110,
+                stream_connection_id: Some(42),
+                shared_key: None,
+            }
+            .to_line(local_addr, remote_addr),
+            "STREAM 192.168.1.2:7000 192.168.1.3:52311 110 42 -"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_is_private() {
+        use std::{fs, os::unix::fs::PermissionsExt};
+
+        let path = std::env::temp_dir().join(format!("keylog-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        KeyLogFile::open(&path).unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,10 @@
+pub mod software;
+
+/// Authentication coprocessor used by MFi variant of pair-setup.
+pub trait MfiAuthenticator: Send + Sync + 'static {
+    /// Certificate of the coprocessor, sent to the controller as is
+    fn certificate(&self) -> Vec<u8>;
+
+    /// Signs challenge derived from pair-setup session key
+    fn sign(&self, challenge: &[u8]) -> Vec<u8>;
+}
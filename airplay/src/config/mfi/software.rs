@@ -0,0 +1,33 @@
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+
+use super::MfiAuthenticator;
+
+/// Authenticator without hardware, controllers validating certificates will refuse it.
+/// Useful for tests and development only.
+pub struct SoftwareMfiAuthenticator {
+    signing_key: SigningKey,
+    certificate: Vec<u8>,
+}
+
+impl SoftwareMfiAuthenticator {
+    pub fn new(secret: [u8; 32], certificate: Vec<u8>) -> Self {
+        Self {
+            signing_key: SigningKey::from_bytes(&secret),
+            certificate,
+        }
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.signing_key.verifying_key()
+    }
+}
+
+impl MfiAuthenticator for SoftwareMfiAuthenticator {
+    fn certificate(&self) -> Vec<u8> {
+        self.certificate.clone()
+    }
+
+    fn sign(&self, challenge: &[u8]) -> Vec<u8> {
+        self.signing_key.sign(challenge).to_bytes().to_vec()
+    }
+}
@@ -1,14 +1,31 @@
+use std::{sync::Arc, time::Duration};
+
+pub use access::{AccessDenied, AccessPolicy, Cidr, CidrError, Peer, Rule};
+pub use approval::{Approval, PairingApproval, PairingRequest};
 use bitflags::bitflags;
 use derivative::Derivative;
+#[cfg(feature = "fairplay")]
+pub use fairplay::ShairplayFairPlay;
+pub use fairplay::{FairPlayError, FairPlayProvider};
 pub use keychain::{Keychain, default::DefaultKeychain};
+pub use keylog::{KeyLog, KeyLogEntry, KeyLogFile};
 pub use macaddr::MacAddr6;
-pub use pin::{PinCode, PinError};
+pub use mfi::{MfiAuthenticator, software::SoftwareMfiAuthenticator};
+pub use pin::{PinCode, PinDisplay, PinError};
+
+// Needed by custom FairPlay providers
+pub use crate::crypto::{AesKey128, Secret};
 
+mod access;
+mod approval;
+mod fairplay;
 mod keychain;
+mod keylog;
+mod mfi;
 mod pin;
 
-#[derive(Debug, Derivative)]
-#[derivative(Default)]
+#[derive(Derivative)]
+#[derivative(Debug, Default)]
 pub struct Config<ADev, VDev, KC> {
     pub mac_addr: MacAddr6,
     pub features: Features,
@@ -21,9 +38,30 @@ pub struct Config<ADev, VDev, KC> {
     #[derivative(Default(value = "env!(\"CARGO_PKG_VERSION\").to_string()"))]
     pub fw_version: String,
 
+    /// With legacy pairing senders must pair using the PIN before pair-verify, it has 4 digits there
     pub pin: Option<PinCode>,
+    /// Password for RTSP requests, checked with Digest authentication
+    #[derivative(Debug = "ignore")]
+    pub password: Option<Secret<String>>,
+    /// If set, every `/pair-pin-start` generates a new random PIN instead of using [`Self::pin`]
+    #[derivative(Debug = "ignore")]
+    pub pin_display: Option<Arc<dyn PinDisplay>>,
+    /// Enables MFi variant of HomeKit pair-setup
+    #[derivative(Debug = "ignore")]
+    pub mfi: Option<Arc<dyn MfiAuthenticator>>,
+    /// Handles `/fp-setup` and unwraps keys of legacy streams, built-in one with `fairplay` feature
+    #[derivative(Debug = "ignore", Default(value = "fairplay::builtin()"))]
+    pub fairplay: Option<Arc<dyn FairPlayProvider>>,
+    /// Asked before a new HomeKit controller is saved into keychain, otherwise everyone is trusted
+    #[derivative(Debug = "ignore")]
+    pub pairing_approval: Option<Arc<dyn PairingApproval>>,
+    /// Receives every established key, for debugging with captures only
+    #[derivative(Debug = "ignore")]
+    pub keylog: Option<Arc<dyn KeyLog>>,
+    pub access: AccessPolicy,
     pub keychain: KC,
     pub pairing: Pairing,
+    pub pairing_limits: PairingLimits,
     pub audio: Audio<ADev>,
     pub video: Video<VDev>,
 }
@@ -32,7 +70,79 @@ pub struct Config<ADev, VDev, KC> {
 pub enum Pairing {
     #[default]
     Legacy,
-    HomeKit,
+    HomeKit {
+        mode: PairingMode,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PairingMode {
+    /// Pairing lasts for a session only, PIN is asked every time and keychain is never written
+    Transient,
+    /// Controllers are saved into keychain and must pass pair-verify on every connection
+    Persistent,
+    #[default]
+    Both,
+}
+
+impl PairingMode {
+    pub fn allows_transient(self) -> bool {
+        matches!(self, Self::Transient | Self::Both)
+    }
+
+    pub fn allows_persistent(self) -> bool {
+        matches!(self, Self::Persistent | Self::Both)
+    }
+}
+
+impl<A, V, K> Config<A, V, K> {
+    /// Features adjusted to the chosen pairing, must be used for advertising instead of
+    /// [`Self::features`].
+    pub fn advertised_features(&self) -> Features {
+        let mut features = self.features;
+        if self.fairplay.is_none() {
+            features.remove(Features::MFiSoft_FairPlay | Features::FPSAPv2p5_AES_GCM);
+        }
+        match self.pairing {
+            Pairing::Legacy => features.remove(Features::TransientPairing),
+            Pairing::HomeKit { mode } => {
+                features.insert(Features::HomeKitPairing);
+                features.set(Features::TransientPairing, mode.allows_transient());
+            }
+        }
+
+        features
+    }
+
+    /// Status flags to be advertised along with features.
+    pub fn status_flags(&self) -> StatusFlags {
+        let mut flags = StatusFlags::empty();
+        flags.set(StatusFlags::PasswordRequired, self.password.is_some());
+        flags.set(
+            StatusFlags::PinRequired,
+            matches!(self.pairing, Pairing::Legacy)
+                && (self.pin.is_some() || self.pin_display.is_some()),
+        );
+
+        flags
+    }
+}
+
+/// Brute-force protection of pair-setup, shared by all connections of the receiver.
+#[derive(Debug, Clone, Copy, Derivative)]
+#[derivative(Default)]
+pub struct PairingLimits {
+    /// Failed attempts before pairing is locked until restart
+    #[derivative(Default(value = "100"))]
+    pub max_tries: u32,
+    /// Failed attempts allowed without any delay
+    #[derivative(Default(value = "3"))]
+    pub backoff_after: u32,
+    /// Delay after the first throttled attempt, doubled with every next failure
+    #[derivative(Default(value = "Duration::from_secs(1)"))]
+    pub base_delay: Duration,
+    #[derivative(Default(value = "Duration::from_secs(60 * 60)"))]
+    pub max_delay: Duration,
 }
 
 #[derive(Derivative)]
@@ -83,6 +193,7 @@ bitflags! {
         const ReceiveAudioALAC = 1 << 19;
         const ReceiveAudioAAC_LC = 1 << 20;
         const Unknown21 = 1 << 21;
+        /// Named after audio, but video streams without keys are accepted with it too
         const AudioUnencrypted = 1 << 22;
         const RSA_Auth = 1 << 23;
         const Unknown24 = 1 << 24;
@@ -126,11 +237,33 @@ bitflags! {
     }
 }
 
+bitflags! {
+    #[repr(transparent)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct StatusFlags: u32 {
+        const ProblemDetected = 1 << 0;
+        const NotConfigured = 1 << 1;
+        const AudioCableAttached = 1 << 2;
+        const PinRequired = 1 << 3;
+        const SupportsAirPlayFromCloud = 1 << 6;
+        const PasswordRequired = 1 << 7;
+        const OneTimePairingRequired = 1 << 9;
+        const DeviceSetupForHKAccessControl = 1 << 10;
+        const DeviceSupportsRelay = 1 << 11;
+        const SilentPrimary = 1 << 12;
+        const TightSyncIsGroupLeader = 1 << 13;
+        const TightSyncBuddyNotReachable = 1 << 14;
+        const IsAppleMusicSubscriber = 1 << 15;
+        const CloudLibraryIsOn = 1 << 16;
+        const ReceiverSessionIsActive = 1 << 17;
+    }
+}
+
 /// Default features that supported by the current version of the crate.
 /// Modify it if you make any changes into the code.
 impl Default for Features {
     fn default() -> Self {
-        Self::Video
+        let features = Self::Video
             | Self::Photo
             | Self::VideoHTTPLiveStreaming
             | Self::Unknown6
@@ -147,10 +280,11 @@ impl Default for Features {
             | Self::ReceiveAudioALAC
             | Self::ReceiveAudioAAC_LC
 
-            // A glitch whether /fp-setup is called, but the audio/video data is clear
-            | Self::MFiSoft_FairPlay
             | Self::HomeKitPairing
+            // Accepts streams without any keys, audio and video alike
             // | Self::AudioUnencrypted
+            // Encrypts realtime audio's control packets with keys of the HomeKit session
+            // | Self::ControlChannelEncrypt
 
             // Seems like needed for a GET /info call
             | Self::UnifiedAdvertisingInfo
@@ -158,6 +292,16 @@ impl Default for Features {
             // Enable AirPlay2, using buffered audio (e.g. Apple Music)
             | Self::BufferedAudio
             | Self::NTPClock
-            | Self::PTPClock
+            | Self::PTPClock;
+
+        // A glitch whether /fp-setup is called, but the audio/video data is clear
+        #[cfg(feature = "fairplay")]
+        let features = features | Self::MFiSoft_FairPlay;
+
+        // Key of FairPlay SAP v2.5 is used with AES-GCM then, CBC/CTR are kept for others
+        #[cfg(feature = "fairplay")]
+        let features = features | Self::FPSAPv2p5_AES_GCM;
+
+        features
     }
 }
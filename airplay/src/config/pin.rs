@@ -1,5 +1,6 @@
 use std::fmt;
 
+use rand::{Rng, RngExt};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -10,15 +11,51 @@ pub enum PinError {
     InvalidDigit,
 }
 
-// 8x u8 for easy alignments, don't really wanna do u32 math
-#[derive(Debug, Copy, Clone)]
-pub struct PinCode(pub [u8; 8]);
+// Digits as u8 for easy alignments, don't really wanna do u32 math
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PinCode {
+    /// Setup code of HomeKit pairing, shown as `XXX-XX-XXX`
+    HomeKit([u8; 8]),
+    /// AirPlay 1 senders ask for 4 digits only
+    Legacy([u8; 4]),
+}
+
+/// Shows PIN to the user while pairing is in progress, e.g. on the TV screen.
+pub trait PinDisplay: Send + Sync + 'static {
+    fn show(&self, pin: PinCode);
+    fn hide(&self);
+}
+
+impl PinCode {
+    /// Generates random HomeKit PIN, skipping sequences that aren't allowed.
+    pub fn random(mut rng: impl Rng) -> Self {
+        loop {
+            let digits: [u8; 8] = std::array::from_fn(|_| rng.random_range(0..10));
+            if let Ok(pin) = Self::try_from(digits) {
+                return pin;
+            }
+        }
+    }
+
+    /// Generates random 4-digit PIN for legacy pairing.
+    pub fn random_legacy(mut rng: impl Rng) -> Self {
+        Self::Legacy(std::array::from_fn(|_| rng.random_range(0..10)))
+    }
+
+    pub fn digits(&self) -> &[u8] {
+        match self {
+            Self::HomeKit(digits) => digits,
+            Self::Legacy(digits) => digits,
+        }
+    }
+}
 
 impl fmt::Display for PinCode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let [s, u, p, w, o, r, l, d] = self.0;
-
-        write!(f, "{s}{u}{p}-{w}{o}-{r}{l}{d}")
+        match *self {
+            Self::HomeKit([s, u, p, w, o, r, l, d]) => write!(f, "{s}{u}{p}-{w}{o}-{r}{l}{d}"),
+            Self::Legacy([p, i, n, s]) => write!(f, "{p}{i}{n}{s}"),
+        }
     }
 }
 
@@ -49,6 +86,18 @@ impl TryFrom<[u8; 8]> for PinCode {
             return Err(PinError::InvalidDigit);
         }
 
-        Ok(Self(value))
+        Ok(Self::HomeKit(value))
+    }
+}
+
+impl TryFrom<[u8; 4]> for PinCode {
+    type Error = PinError;
+
+    fn try_from(value: [u8; 4]) -> Result<Self, Self::Error> {
+        if value.iter().any(|x| *x >= 10) {
+            return Err(PinError::InvalidDigit);
+        }
+
+        Ok(Self::Legacy(value))
     }
 }
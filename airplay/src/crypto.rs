@@ -1,6 +1,76 @@
-pub type AesKey128 = [u8; 16];
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+};
+
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+pub type AesKey128 = Secret<[u8; 16]>;
 pub type AesIv128 = [u8; 16];
-pub type ChaCha20Poly1305Key = [u8; 32];
+pub type ChaCha20Poly1305Key = Secret<[u8; 32]>;
+
+/// Key material zeroized on drop. It's redacted in `Debug` unless `log-secrets` feature is
+/// enabled, so keys don't leak into traces by accident.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret<T: Zeroize>(T);
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T: Zeroize> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<const N: usize> TryFrom<&[u8]> for Secret<[u8; N]> {
+    type Error = std::array::TryFromSliceError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        value.try_into().map(Self)
+    }
+}
+
+impl<T: Zeroize> Deref for Secret<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T: Zeroize> DerefMut for Secret<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<T: Zeroize + AsRef<[u8]>> AsRef<[u8]> for Secret<T> {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+impl<T: Zeroize + fmt::Debug> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if cfg!(feature = "log-secrets") {
+            f.debug_tuple("Secret").field(&self.0).finish()
+        } else {
+            f.write_str("Secret(<redacted>)")
+        }
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: Zeroize> ZeroizeOnDrop for Secret<T> {}
 
 pub fn hkdf(input: &[u8], salt: &[u8], info: &[u8]) -> [u8; 32] {
     use hkdf::Hkdf;
@@ -26,3 +96,18 @@ pub fn sha512_two_step(x: &[u8], y: &[u8]) -> [u8; 16] {
         .first_chunk()
         .expect("sha512 must return at least 64 elements")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{AesKey128, Secret};
+
+    #[test]
+    #[cfg(not(feature = "log-secrets"))]
+    fn secret_is_redacted() {
+        let key = AesKey128::from([0xAB; 16]);
+        assert_eq!(format!("{key:?}"), "Secret(<redacted>)");
+
+        let key_material = Secret::new(vec![0xAB; 32]);
+        assert_eq!(format!("{key_material:?}"), "Secret(<redacted>)");
+    }
+}
@@ -2,6 +2,9 @@ pub mod config;
 pub mod playback;
 pub mod rtsp;
 
+#[cfg(feature = "capture")]
+pub mod capture;
+
 pub(crate) mod crypto;
 pub(crate) mod pairing;
 pub(crate) mod streaming;
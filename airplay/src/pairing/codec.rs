@@ -80,9 +80,9 @@ where
                 && session_key.upgrade_channel
             {
                 self.hap_encoder
-                    .replace(HAPEncoder::new(session_key.key_material));
+                    .replace(HAPEncoder::new(&session_key.key_material));
                 self.hap_decoder
-                    .replace(HAPDecoder::new(session_key.key_material));
+                    .replace(HAPDecoder::new(&session_key.key_material));
             }
 
             self.inner_encoder.encode(item, dst)
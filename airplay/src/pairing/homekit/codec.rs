@@ -7,7 +7,7 @@ use chacha20poly1305::{
 };
 use tokio_util::codec::{Decoder, Encoder};
 
-use crate::crypto::hkdf;
+use crate::crypto::{ChaCha20Poly1305Key, hkdf};
 
 // 96-bit nonce
 const NONCE_LEN: usize = 12;
@@ -19,7 +19,7 @@ const PKT_SIZE_LEN: usize = 2;
 const PAYLOAD_SIZE: usize = 1024;
 
 pub struct HAPDecoder {
-    key: [u8; 32],
+    key: ChaCha20Poly1305Key,
     count: u64,
 }
 
@@ -28,8 +28,12 @@ impl HAPDecoder {
         const SALT: &[u8] = b"Control-Salt";
         const INFO: &[u8] = b"Control-Write-Encryption-Key";
 
+        Self::with_salt_and_info(shared_secret, SALT, INFO)
+    }
+
+    pub fn with_salt_and_info(shared_secret: impl AsRef<[u8]>, salt: &[u8], info: &[u8]) -> Self {
         Self {
-            key: hkdf(shared_secret.as_ref(), SALT, INFO),
+            key: hkdf(shared_secret.as_ref(), salt, info).into(),
             count: 0,
         }
     }
@@ -79,7 +83,7 @@ impl Decoder for HAPDecoder {
             buf
         };
 
-        let cipher = ChaCha20Poly1305::new(&Key::from(self.key));
+        let cipher = ChaCha20Poly1305::new(&Key::from(*self.key));
         cipher
             .decrypt_inout_detached(
                 &Nonce::from(nonce),
@@ -97,7 +101,7 @@ impl Decoder for HAPDecoder {
 }
 
 pub struct HAPEncoder {
-    key: [u8; 32],
+    key: ChaCha20Poly1305Key,
     count: u64,
 }
 
@@ -106,8 +110,12 @@ impl HAPEncoder {
         const SALT: &[u8] = b"Control-Salt";
         const INFO: &[u8] = b"Control-Read-Encryption-Key";
 
+        Self::with_salt_and_info(shared_secret, SALT, INFO)
+    }
+
+    pub fn with_salt_and_info(shared_secret: impl AsRef<[u8]>, salt: &[u8], info: &[u8]) -> Self {
         Self {
-            key: hkdf(shared_secret.as_ref(), SALT, INFO),
+            key: hkdf(shared_secret.as_ref(), salt, info).into(),
             count: 0,
         }
     }
@@ -131,7 +139,7 @@ impl<T: AsRef<[u8]>> Encoder<T> for HAPEncoder {
 
         for b in blocks {
             let len = b.len();
-            let cipher = ChaCha20Poly1305::new(&Key::from(self.key));
+            let cipher = ChaCha20Poly1305::new(&Key::from(*self.key));
             let aad = (len as u16).to_le_bytes();
             let nonce = {
                 let mut buf = [0u8; NONCE_LEN];
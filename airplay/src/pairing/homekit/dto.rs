@@ -146,6 +146,7 @@ impl_tlv8!(Proof, Vec<u8>, |v: &[u8]| v.len());
 impl_tlv8!(EncryptedData, Vec<u8>, |v: &[u8]| v.len());
 impl_tlv8!(Identifier, Vec<u8>, |v: &[u8]| v.len());
 impl_tlv8!(Signature, Vec<u8>, |v: &[u8]| v.len());
+impl_tlv8!(Certificate, Vec<u8>, |v: &[u8]| v.len());
 
 impl Tlv8 for ErrorCode {
     const TAG: TagCode = TagCode::Error;
@@ -157,6 +158,20 @@ impl Tlv8 for ErrorCode {
     }
 }
 
+/// Seconds to wait before the next attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryDelay(pub u16);
+
+impl Tlv8 for RetryDelay {
+    const TAG: TagCode = TagCode::RetryDelay;
+    type Param = ();
+    type Value = Self;
+
+    fn length(_: &Self::Value) -> usize {
+        mem::size_of::<u16>()
+    }
+}
+
 impl Tlv8 for PairingFlags {
     const TAG: TagCode = TagCode::Flags;
     type Param = ();
@@ -3,7 +3,7 @@ use std::iter;
 use thiserror::Error;
 
 use super::{
-    super::dto::{ErrorCode, PairingFlags, TagCode, Tlv8, TypedCode},
+    super::dto::{ErrorCode, PairingFlags, RetryDelay, TagCode, Tlv8, TypedCode},
     Tlv8Decode, Tlv8Encode, Tlv8Rejection,
 };
 
@@ -174,6 +174,12 @@ impl Decode<()> for PairingFlags {
     }
 }
 
+impl Encode<()> for RetryDelay {
+    fn encode(self) -> impl Iterator<Item = u8> {
+        self.0.to_le_bytes().into_iter()
+    }
+}
+
 impl Encode<()> for ErrorCode {
     fn encode(self) -> impl Iterator<Item = u8> {
         iter::once(self as u8)
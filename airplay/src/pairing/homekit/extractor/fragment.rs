@@ -0,0 +1,263 @@
+//! Fragmented exchange of TLV8 messages.
+//!
+//! A large message is split into `FragmentData` items, every one of them is acknowledged by the
+//! receiving side with an empty `FragmentData`. The last part of the message is `FragmentLast`.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::{Body, to_bytes},
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use http::{
+    HeaderValue, StatusCode,
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+};
+
+use super::{super::dto::TagCode, APPLE_TLV8_MIME};
+
+/// Size of a single outgoing fragment
+const FRAGMENT_LEN: usize = 1024;
+/// Pairing messages are a few KiB at most, even with MFi certificate
+const MAX_MESSAGE_LEN: usize = 32 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Incoming {
+    /// Complete message to be handled
+    Message(Bytes),
+    /// Reply that must be sent back right away
+    Reply(Bytes),
+    /// Reassembled message exceeds [`MAX_MESSAGE_LEN`], it's dropped
+    TooLarge,
+}
+
+#[derive(Debug, Default)]
+pub struct Fragments {
+    inbound: Vec<u8>,
+    outbound: VecDeque<Bytes>,
+    peer_fragments: bool,
+}
+
+impl Fragments {
+    pub fn incoming(&mut self, body: Bytes) -> Incoming {
+        let Some((tag, value)) = parse_fragment(&body) else {
+            return Incoming::Message(body);
+        };
+
+        if tag == TagCode::FragmentData && value.is_empty() {
+            // Acknowledgement of the previous outgoing fragment
+            return Incoming::Reply(self.outbound.pop_front().unwrap_or_else(ack));
+        }
+        if self.inbound.len() + value.len() > MAX_MESSAGE_LEN {
+            self.inbound = Vec::new();
+            return Incoming::TooLarge;
+        }
+
+        self.inbound.extend_from_slice(&value);
+        if tag == TagCode::FragmentData {
+            self.peer_fragments = true;
+            Incoming::Reply(ack())
+        } else {
+            Incoming::Message(Bytes::from(std::mem::take(&mut self.inbound)))
+        }
+    }
+
+    /// Splits the message only if the peer used fragments itself, otherwise it's returned as is.
+    pub fn outgoing(&mut self, body: Bytes) -> Bytes {
+        if !self.peer_fragments || body.len() <= FRAGMENT_LEN {
+            return body;
+        }
+
+        let mut chunks = body.chunks(FRAGMENT_LEN).peekable();
+        while let Some(chunk) = chunks.next() {
+            let tag = if chunks.peek().is_some() {
+                TagCode::FragmentData
+            } else {
+                TagCode::FragmentLast
+            };
+            self.outbound.push_back(encode(tag, chunk));
+        }
+
+        self.outbound.pop_front().unwrap_or_default()
+    }
+}
+
+pub async fn middleware(
+    State(fragments): State<Arc<Mutex<Fragments>>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let (parts, body) = req.into_parts();
+    let Ok(body) = to_bytes(body, MAX_MESSAGE_LEN).await else {
+        fragments.lock().unwrap().inbound = Vec::new();
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    let incoming = fragments.lock().unwrap().incoming(body);
+    let body = match incoming {
+        Incoming::Message(body) => body,
+        Incoming::Reply(reply) => {
+            return (
+                [(CONTENT_TYPE, HeaderValue::from_static(APPLE_TLV8_MIME))],
+                reply,
+            )
+                .into_response();
+        }
+        Incoming::TooLarge => {
+            tracing::warn!("fragmented message is too large");
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+    };
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    let (mut parts, body) = response.into_parts();
+    let Ok(body) = to_bytes(body, MAX_MESSAGE_LEN).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+
+    let body = fragments.lock().unwrap().outgoing(body);
+    parts.headers.remove(CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Message is a fragment only if it consists of fragment items solely.
+fn parse_fragment(mut buf: &[u8]) -> Option<(TagCode, Vec<u8>)> {
+    let mut kind = None;
+    let mut value = Vec::new();
+    while let Some((&[tag, len], remain)) = buf.split_first_chunk() {
+        let tag = TagCode::from_repr(tag)
+            .filter(|tag| matches!(tag, TagCode::FragmentData | TagCode::FragmentLast))?;
+        if kind.is_some_and(|kind| kind != tag) {
+            return None;
+        }
+        let (data, remain) = remain.split_at_checked(len.into())?;
+
+        kind = Some(tag);
+        value.extend_from_slice(data);
+        buf = remain;
+    }
+
+    kind.map(|kind| (kind, value))
+}
+
+fn encode(tag: TagCode, data: &[u8]) -> Bytes {
+    let mut buf = Vec::with_capacity(data.len() + 2 * data.len().div_ceil(0xFF));
+    for chunk in data.chunks(0xFF) {
+        buf.push(tag as u8);
+        buf.push(chunk.len() as u8);
+        buf.extend_from_slice(chunk);
+    }
+    Bytes::from(buf)
+}
+
+fn ack() -> Bytes {
+    Bytes::from_static(&[TagCode::FragmentData as u8, 0])
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::{
+        super::super::dto::TagCode, FRAGMENT_LEN, Fragments, Incoming, MAX_MESSAGE_LEN, ack, encode,
+    };
+
+    #[test]
+    fn passthrough_regular_message() {
+        let mut fragments = Fragments::default();
+        let msg = encode(TagCode::PublicKey, &[1; 300]);
+
+        assert_eq!(
+            fragments.incoming(msg.clone()),
+            Incoming::Message(msg.clone())
+        );
+
+        let response = Bytes::from(vec![7; FRAGMENT_LEN * 2]);
+        assert_eq!(fragments.outgoing(response.clone()), response);
+    }
+
+    #[test]
+    fn reassemble_incoming() {
+        let mut fragments = Fragments::default();
+        let msg = [
+            encode(TagCode::PublicKey, &[1; 400]),
+            encode(TagCode::Proof, &[2; 64]),
+        ]
+        .concat();
+        let (first, second) = msg.split_at(300);
+
+        assert_eq!(
+            fragments.incoming(encode(TagCode::FragmentData, first)),
+            Incoming::Reply(ack())
+        );
+        assert_eq!(
+            fragments.incoming(encode(TagCode::FragmentLast, second)),
+            Incoming::Message(Bytes::from(msg))
+        );
+    }
+
+    #[test]
+    fn fragment_outgoing_after_peer() {
+        let mut fragments = Fragments::default();
+        fragments.incoming(encode(TagCode::FragmentData, &[1, 2, 3]));
+        fragments.incoming(encode(TagCode::FragmentLast, &[4, 5]));
+
+        let response = (0..FRAGMENT_LEN * 2 + 10)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        let first = fragments.outgoing(Bytes::from(response.clone()));
+        assert_eq!(
+            first,
+            encode(TagCode::FragmentData, &response[..FRAGMENT_LEN])
+        );
+
+        assert_eq!(
+            fragments.incoming(ack()),
+            Incoming::Reply(encode(
+                TagCode::FragmentData,
+                &response[FRAGMENT_LEN..FRAGMENT_LEN * 2]
+            ))
+        );
+        assert_eq!(
+            fragments.incoming(ack()),
+            Incoming::Reply(encode(TagCode::FragmentLast, &response[FRAGMENT_LEN * 2..]))
+        );
+    }
+
+    #[test]
+    fn mixed_tags_are_not_fragment() {
+        let mut fragments = Fragments::default();
+        let msg = Bytes::from(
+            [
+                encode(TagCode::FragmentData, &[1, 2]),
+                encode(TagCode::PublicKey, &[3]),
+            ]
+            .concat(),
+        );
+
+        assert_eq!(fragments.incoming(msg.clone()), Incoming::Message(msg));
+    }
+
+    #[test]
+    fn oversized_message_is_dropped() {
+        let mut fragments = Fragments::default();
+        let chunk = encode(TagCode::FragmentData, &[1; FRAGMENT_LEN]);
+        for _ in 0..MAX_MESSAGE_LEN / FRAGMENT_LEN {
+            assert_eq!(fragments.incoming(chunk.clone()), Incoming::Reply(ack()));
+        }
+        assert_eq!(fragments.incoming(chunk), Incoming::TooLarge);
+
+        // Next message starts from scratch
+        let msg = encode(TagCode::FragmentLast, &[2; 10]);
+        assert_eq!(
+            fragments.incoming(msg),
+            Incoming::Message(Bytes::from(vec![2; 10]))
+        );
+    }
+}
@@ -8,6 +8,8 @@ use thiserror::Error;
 
 use super::dto::{TagCode, Tlv8Pack};
 
+pub mod fragment;
+
 mod endec;
 
 const APPLE_TLV8_MIME: &str = "application/pairing+tlv8";
@@ -12,13 +12,14 @@ use yoke::{Yoke, erased::ErasedArcCart};
 use super::{
     super::{SessionKey, SharedSessionKey},
     dto::{
-        EncryptedData, ErrorCode, Identifier, Method, PairingFlags, PairingState, Proof, PublicKey,
-        Salt, Signature, method, state,
+        Certificate, EncryptedData, ErrorCode, Identifier, Method, PairingFlags, PairingState,
+        Proof, PublicKey, RetryDelay, Salt, Signature, StateCode, method, state,
     },
     extractor::TaggedValue,
     state::ServiceState,
+    throttle::{Attempt, Throttled},
 };
-use crate::config::Keychain;
+use crate::config::{Approval, Keychain, PairingRequest, Peer};
 
 pub mod setup;
 pub mod verify;
@@ -27,9 +28,12 @@ pub mod verify;
 
 // PairingFlags are optional
 type PSM1Msg = TaggedValue<(PairingState<state::M1>, Method<method::PairSetup>)>;
+type PSM1AuthMsg = TaggedValue<(PairingState<state::M1>, Method<method::PairSetupAuth>)>;
 type PSM2Msg = TaggedValue<(PairingState<state::M2>, PublicKey, Salt, PairingFlags)>;
 type PSM3Msg = TaggedValue<(PairingState<state::M3>, PublicKey, Proof)>;
 type PSM4Msg = TaggedValue<(PairingState<state::M4>, Proof)>;
+type PSM4AuthMsg = TaggedValue<(PairingState<state::M4>, Proof, EncryptedData)>;
+type PSM4MsgSub = TaggedValue<(Signature, Certificate)>;
 type PSM5Msg = TaggedValue<(PairingState<state::M5>, EncryptedData)>;
 type PSM5MsgSub = TaggedValue<(Identifier, PublicKey, Signature)>;
 type PSM6MsgSub = TaggedValue<(Identifier, PublicKey, Signature)>;
@@ -45,49 +49,76 @@ type PVM3MsgSub = TaggedValue<(Identifier, Signature)>;
 type PVM4Msg = TaggedValue<PairingState<state::M4>>;
 
 type ErrorResponse<S> = TaggedValue<(PairingState<S>, ErrorCode)>;
+type BackoffResponse<S> = TaggedValue<(PairingState<S>, ErrorCode, RetryDelay)>;
+
+struct HidePinGuard<'a>(&'a ServiceState);
+
+impl Drop for HidePinGuard<'_> {
+    fn drop(&mut self) {
+        self.0.pin.hide();
+    }
+}
 
 pub async fn pair_setup<K>(
     State(state): State<Arc<ServiceState>>,
     Extension(keychain): Extension<Yoke<&'static K, ErasedArcCart>>,
+    Extension(session_key): Extension<SharedSessionKey>,
     bytes: Bytes,
 ) -> Result<Response, Response>
 where
     K: Keychain,
 {
-    // NB : unsupported, probably never-ever will
-    let Err(_) = TaggedValue::<Method<method::PairSetupAuth>>::from_bytes(&bytes) else {
-        return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "MFi authentication not supported",
-        )
-            .into_response());
-    };
+    let mfi = PSM1AuthMsg::from_bytes(&bytes).is_ok();
+    if PSM1Msg::from_bytes(&bytes).is_ok() || mfi {
+        if mfi && state.mfi.is_none() {
+            tracing::warn!("MFi pair-setup requested, but no authenticator configured");
+            let response: ErrorResponse<state::M2> = TaggedValue(((), ErrorCode::Unavailable));
+            return Err(response.into_response());
+        }
 
-    if PSM1Msg::from_bytes(&bytes).is_ok() {
         let flags = TaggedValue::<PairingFlags>::from_bytes(&bytes)
             .map(|x| x.0)
             .unwrap_or_default();
+        let transient = flags.contains(PairingFlags::TRANSIENT);
+        if (transient && !state.mode.allows_transient())
+            || (!transient && !state.mode.allows_persistent())
+        {
+            tracing::warn!(%transient, mode = ?state.mode, "pairing mode not allowed");
+            let response: ErrorResponse<state::M2> = TaggedValue(((), ErrorCode::Unavailable));
+            return Err(response.into_response());
+        }
 
-        Ok(pair_setup_m1m2(&state, flags).into_response())
+        pair_setup_throttle::<state::M2>(&state)?;
+        Ok(pair_setup_m1m2(&state, flags, mfi).into_response())
     } else if let Ok(TaggedValue(((), pubkey, proof))) = PSM3Msg::from_bytes(&bytes) {
-        pair_setup_m3m4(&state, &pubkey, &proof)
-            .map(IntoResponse::into_response)
+        let attempt = pair_setup_throttle::<state::M4>(&state).inspect_err(|_| state.pin.hide())?;
+        pair_setup_m3m4(&state, attempt, &session_key, &pubkey, &proof)
             .map_err(IntoResponse::into_response)
+            .inspect_err(|_| state.pin.hide())
     } else {
+        // Whatever happens here, pairing is over
+        let _guard = HidePinGuard(&state);
+        if !state.mode.allows_persistent() {
+            tracing::warn!("persistent pairing not allowed");
+            let response: ErrorResponse<state::M6> = TaggedValue(((), ErrorCode::Unavailable));
+            return Err(response.into_response());
+        }
+
         match PSM5Msg::from_bytes(&bytes) {
             Ok(TaggedValue(((), mut enc_tlv))) => {
                 pair_setup_m5m6_dec(&state, &mut enc_tlv).map_err(IntoResponse::into_response)?;
 
                 match PSM5MsgSub::from_bytes(&enc_tlv) {
                     Ok(TaggedValue((identifier, pubkey, signature))) => {
-                        let sub_tlv = pair_setup_m5m6(
-                            &state,
-                            *keychain.get(),
-                            &identifier,
-                            &pubkey,
-                            &signature,
-                        )
-                        .map_err(IntoResponse::into_response)?;
+                        check_access(&state, &identifier)?;
+                        pair_setup_m5m6_verify(&state, &identifier, &pubkey, &signature)
+                            .map_err(IntoResponse::into_response)?;
+                        pair_setup_approve(&state, &identifier, &pubkey)
+                            .await
+                            .map_err(IntoResponse::into_response)?;
+                        let sub_tlv =
+                            pair_setup_m5m6(&state, *keychain.get(), &identifier, &pubkey)
+                                .map_err(IntoResponse::into_response)?;
                         let msg = sub_tlv.bytes().collect::<Vec<u8>>();
 
                         pair_setup_m5m6_enc(&state, msg)
@@ -111,6 +142,12 @@ pub async fn pair_verify<K>(
 where
     K: Keychain,
 {
+    if !state.mode.allows_persistent() {
+        tracing::warn!("pair-verify without persistent pairing");
+        let response: ErrorResponse<state::M2> = TaggedValue(((), ErrorCode::Unavailable));
+        return Err(response.into_response());
+    }
+
     if let Ok(TaggedValue(((), pubkey))) = PVM1Msg::from_bytes(&bytes) {
         let (accessory_tmp_pubkey, sub_tlv) = pair_verify_m1m2(&state, *keychain.get(), &pubkey)
             .map_err(IntoResponse::into_response)?;
@@ -125,15 +162,18 @@ where
                 pair_verify_m3m4_dec(&state, &mut enc_tlv).map_err(IntoResponse::into_response)?;
 
                 match PVM3MsgSub::from_bytes(&enc_tlv) {
-                    Ok(TaggedValue((device_id, device_signature))) => pair_verify_m3m4(
-                        &state,
-                        &session_key,
-                        *keychain.get(),
-                        &device_id,
-                        &device_signature,
-                    )
-                    .map(IntoResponse::into_response)
-                    .map_err(IntoResponse::into_response),
+                    Ok(TaggedValue((device_id, device_signature))) => {
+                        check_access(&state, &device_id)?;
+                        pair_verify_m3m4(
+                            &state,
+                            &session_key,
+                            *keychain.get(),
+                            &device_id,
+                            &device_signature,
+                        )
+                        .map(IntoResponse::into_response)
+                        .map_err(IntoResponse::into_response)
+                    }
                     Err(err) => Err(err.into_response()),
                 }
             }
@@ -142,52 +182,162 @@ where
     }
 }
 
-fn pair_setup_m1m2(state: &ServiceState, flags: PairingFlags) -> PSM2Msg {
-    let (pubkey, salt) = state.setup_state.lock().unwrap().m1_m2(rand::rng());
+fn check_access(state: &ServiceState, device_id: &[u8]) -> Result<(), Response> {
+    let peer = Peer {
+        pairing_id: Some(device_id),
+        ..Peer::new(state.remote_addr.ip())
+    };
+    state.access.check_pairing(&peer).map_err(|err| {
+        tracing::warn!(%err, remote_addr = %state.remote_addr, "pairing rejected");
+        StatusCode::from(err).into_response()
+    })
+}
+
+fn pair_setup_throttle<S: StateCode>(state: &ServiceState) -> Result<Attempt<'_>, Response> {
+    match state.throttle.check() {
+        Ok(attempt) => Ok(attempt),
+        Err(Throttled::Backoff(delay)) => {
+            let secs = delay.as_secs() + u64::from(delay.subsec_nanos() > 0);
+            let delay = RetryDelay(secs.try_into().unwrap_or(u16::MAX));
+            tracing::warn!(?delay, "pair-setup throttled");
+            let response: BackoffResponse<S> = TaggedValue(((), ErrorCode::Backoff, delay));
+            Err(response.into_response())
+        }
+        Err(Throttled::MaxTries) => {
+            tracing::warn!("pair-setup locked, too many failed attempts");
+            let response: ErrorResponse<S> = TaggedValue(((), ErrorCode::MaxTries));
+            Err(response.into_response())
+        }
+    }
+}
+
+fn pair_setup_m1m2(state: &ServiceState, flags: PairingFlags, mfi: bool) -> PSM2Msg {
+    let mut inner = state.setup_state.lock().unwrap();
+    inner.request_mfi(mfi);
+    inner.request_transient(flags.contains(PairingFlags::TRANSIENT));
+    let (pubkey, salt) = inner.m1_m2(rand::rng(), state.pin.current());
     TaggedValue(((), pubkey, salt, flags))
 }
 
 fn pair_setup_m3m4(
     state: &ServiceState,
+    attempt: Attempt<'_>,
+    session_key: &SharedSessionKey,
     pubkey: &[u8],
     proof: &[u8],
-) -> Result<PSM4Msg, ErrorResponse<state::M4>> {
+) -> Result<Response, ErrorResponse<state::M4>> {
+    let mut inner = state.setup_state.lock().unwrap();
+    let proof = match inner.m3_m4(pubkey, proof) {
+        Ok(proof) => proof,
+        Err(err) => {
+            if matches!(err, ErrorCode::Authentication) {
+                attempt.failure();
+            }
+            return Err(TaggedValue(((), err)));
+        }
+    };
+
+    let response = if inner.mfi_requested() {
+        let Some(mfi) = &state.mfi else {
+            return Err(TaggedValue(((), ErrorCode::Unavailable)));
+        };
+
+        let challenge = inner
+            .m3_m4_mfi_challenge()
+            .map_err(|err| TaggedValue(((), err)))?;
+        let sub_tlv: PSM4MsgSub = TaggedValue((mfi.sign(&challenge), mfi.certificate()));
+        let mut msg = sub_tlv.bytes().collect::<Vec<u8>>();
+        inner
+            .m3_m4_enc(&mut msg)
+            .map_err(|err| TaggedValue(((), err)))?;
+
+        let response: PSM4AuthMsg = TaggedValue(((), proof, msg));
+        response.into_response()
+    } else {
+        let response: PSM4Msg = TaggedValue(((), proof));
+        response.into_response()
+    };
+
+    // Attempts are reset only once M4 is ready to be sent
+    attempt.success();
+
+    // Transient pairing ends here, SRP session key is used for the channel without pair-verify
+    if let Some(key_material) = inner.transient_session_key() {
+        session_key.replace(SessionKey {
+            key_material: key_material.to_vec().into(),
+            upgrade_channel: true,
+            pairing_id: None,
+        });
+        state.pin.hide();
+        tracing::info!("transient pairing established");
+    }
+
+    Ok(response)
+}
+
+fn pair_setup_m5m6_dec(
+    state: &ServiceState,
+    enc_tlv: &mut Vec<u8>,
+) -> Result<(), ErrorResponse<state::M5>> {
     state
         .setup_state
         .lock()
         .unwrap()
-        .m3_m4(pubkey, proof)
-        .map(|proof| TaggedValue(((), proof)))
+        .m5_m6_dec(enc_tlv)
         .map_err(|err| TaggedValue(((), err)))
 }
 
-fn pair_setup_m5m6_dec(
+fn pair_setup_m5m6_verify(
     state: &ServiceState,
-    enc_tlv: &mut Vec<u8>,
-) -> Result<(), ErrorResponse<state::M5>> {
+    device_id: &[u8],
+    device_pubkey: &[u8],
+    device_signature: &[u8],
+) -> Result<(), ErrorResponse<state::M6>> {
     state
         .setup_state
         .lock()
         .unwrap()
-        .m5_m6_dec(enc_tlv)
+        .m5_m6_verify(device_id, device_pubkey, device_signature)
         .map_err(|err| TaggedValue(((), err)))
 }
 
+async fn pair_setup_approve(
+    state: &ServiceState,
+    device_id: &[u8],
+    device_pubkey: &[u8],
+) -> Result<(), ErrorResponse<state::M6>> {
+    let Some(approval) = &state.approval else {
+        return Ok(());
+    };
+
+    let request = PairingRequest {
+        identifier: device_id.to_vec(),
+        public_key: device_pubkey.to_vec(),
+        remote_addr: state.remote_addr,
+    };
+    match approval.approve(request).await {
+        Approval::Accept => Ok(()),
+        Approval::Deny => {
+            tracing::warn!(remote_addr = %state.remote_addr, "new controller denied");
+            Err(TaggedValue(((), ErrorCode::Authentication)))
+        }
+        Approval::Unavailable => {
+            tracing::warn!(remote_addr = %state.remote_addr, "approval unavailable");
+            Err(TaggedValue(((), ErrorCode::Unavailable)))
+        }
+    }
+}
+
 fn pair_setup_m5m6<K>(
     state: &ServiceState,
     keychain: &K,
     device_id: &[u8],
     device_pubkey: &[u8],
-    device_signature: &[u8],
 ) -> Result<PSM6MsgSub, ErrorResponse<state::M6>>
 where
     K: Keychain,
 {
     let inner = state.setup_state.lock().unwrap();
-    inner
-        .m5_m6_verify(device_id, device_pubkey, device_signature)
-        .map_err(|err| TaggedValue(((), err)))?;
-
     if !keychain.trust(device_id, device_pubkey) {
         return Err(TaggedValue(((), ErrorCode::Authentication)));
     }
@@ -282,10 +432,11 @@ where
         .m3_m4(device_id, device_signature, |msg, signature| {
             keychain.verify(device_id, msg, signature)
         })
-        .inspect(|&shared_secret| {
-            session_key.lock_write().replace(SessionKey {
-                key_material: shared_secret,
+        .inspect(|shared_secret| {
+            session_key.replace(SessionKey {
+                key_material: shared_secret.to_vec().into(),
                 upgrade_channel: true,
+                pairing_id: Some(device_id.to_vec()),
             });
         })
         .map(|_| TaggedValue(()))
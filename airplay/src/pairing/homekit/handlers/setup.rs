@@ -1,5 +1,3 @@
-use std::borrow::Cow;
-
 use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce, aead::AeadInOut};
 use ed25519_dalek::{Signature, VerifyingKey};
 use rand::{Rng, RngExt};
@@ -7,7 +5,13 @@ use sha2::Sha512;
 use srp::{ClientG3072, ServerG3072};
 
 use super::super::dto::ErrorCode;
-use crate::{config::PinCode, crypto::hkdf};
+use crate::{
+    config::PinCode,
+    crypto::{Secret, hkdf},
+};
+
+const PAIR_SETUP_USERNAME: &str = "Pair-Setup";
+const PAIR_SETUP_DEFAULT_PASSWORD: &str = "3939";
 
 type SaltArray = [u8; 16];
 type PrivKeyArray = [u8; 64];
@@ -16,49 +20,69 @@ enum Inner {
     Init,
     AuthStart {
         salt: SaltArray,
-        privkey: PrivKeyArray,
-        verifier: Vec<u8>,
+        privkey: Secret<PrivKeyArray>,
+        verifier: Secret<Vec<u8>>,
     },
     Transient {
-        session_key: Vec<u8>,
+        session_key: Secret<Vec<u8>>,
     },
 }
 
 pub struct State {
-    username: &'static str,
-    password: Cow<'static, str>,
+    mfi: bool,
+    transient: bool,
     inner: Inner,
 }
 
 impl State {
-    pub fn new(pin: Option<PinCode>) -> Self {
-        const PAIR_SETUP_USERNAME: &str = "Pair-Setup";
-        const PAIR_SETUP_DEFAULT_PASSWORD: &str = "3939";
-
-        let username = PAIR_SETUP_USERNAME;
-        let password = if let Some(pin) = pin {
-            Cow::Owned(format!("{pin}"))
-        } else {
-            Cow::Borrowed(PAIR_SETUP_DEFAULT_PASSWORD)
-        };
-
+    pub fn new() -> Self {
         Self {
-            username,
-            password,
+            mfi: false,
+            transient: false,
             inner: Inner::Init,
         }
     }
 
-    pub fn m1_m2(&mut self, mut rand: impl Rng) -> (Vec<u8>, Vec<u8>) {
+    /// Whether M4 must carry MFi proof, it's chosen by the controller at M1.
+    pub fn request_mfi(&mut self, mfi: bool) {
+        self.mfi = mfi;
+    }
+
+    pub fn mfi_requested(&self) -> bool {
+        self.mfi
+    }
+
+    /// Transient pairing skips M5/M6 and pair-verify, nothing is written to keychain.
+    pub fn request_transient(&mut self, transient: bool) {
+        self.transient = transient;
+    }
+
+    /// SRP session key, available after successful M4 of transient pairing only.
+    pub fn transient_session_key(&self) -> Option<&[u8]> {
+        match &self.inner {
+            Inner::Transient { session_key } if self.transient => Some(session_key.as_slice()),
+            _ => None,
+        }
+    }
+
+    /// Without PIN the well-known default password is used.
+    pub fn m1_m2(&mut self, mut rand: impl Rng, pin: Option<PinCode>) -> (Vec<u8>, Vec<u8>) {
         let salt: SaltArray = rand.random();
-        let privkey: PrivKeyArray = rand.random();
+        let privkey = Secret::new(rand.random::<PrivKeyArray>());
 
+        let password = Secret::new(match pin {
+            Some(pin) => pin.to_string(),
+            None => PAIR_SETUP_DEFAULT_PASSWORD.to_string(),
+        });
         let srp_client = ClientG3072::<Sha512>::new_with_options(true);
-        let verifier =
-            srp_client.compute_verifier(self.username.as_bytes(), self.password.as_bytes(), &salt);
+        let verifier = Secret::new(srp_client.compute_verifier(
+            PAIR_SETUP_USERNAME.as_bytes(),
+            password.as_bytes(),
+            &salt,
+        ));
 
         let srp_server = ServerG3072::<Sha512>::new();
-        let pubkey = srp_server.compute_public_ephemeral(&privkey, &verifier);
+        let pubkey = srp_server.compute_public_ephemeral(privkey.as_slice(), &verifier);
 
         self.inner = Inner::AuthStart {
             salt,
@@ -86,9 +110,9 @@ impl State {
         let srp_server = ServerG3072::<Sha512>::new_with_options(true);
 
         let Ok(reply) = srp_server.process_reply(
-            self.username.as_bytes(),
+            PAIR_SETUP_USERNAME.as_bytes(),
             salt,
-            privkey,
+            privkey.as_slice(),
             verifier,
             client_pubkey,
         ) else {
@@ -100,12 +124,44 @@ impl State {
         };
 
         self.inner = Inner::Transient {
-            session_key: session_key.to_vec(),
+            session_key: session_key.to_vec().into(),
         };
 
         Ok(reply.proof().to_vec())
     }
 
+    pub fn m3_m4_mfi_challenge(&self) -> Result<[u8; 32], ErrorCode> {
+        const SALT: &[u8] = b"MFi-Pair-Setup-Salt";
+        const INFO: &[u8] = b"MFi-Pair-Setup-Info";
+
+        let Inner::Transient { session_key } = &self.inner else {
+            return Err(ErrorCode::Busy);
+        };
+
+        Ok(hkdf(session_key, SALT, INFO))
+    }
+
+    pub fn m3_m4_enc(&self, msg: &mut Vec<u8>) -> Result<(), ErrorCode> {
+        const NONCE: &[u8] = b"\0\0\0\0PS-Msg04";
+        const SALT: &[u8] = b"Pair-Setup-Encrypt-Salt";
+        const INFO: &[u8] = b"Pair-Setup-Encrypt-Info";
+
+        let Inner::Transient { session_key } = &self.inner else {
+            return Err(ErrorCode::Busy);
+        };
+
+        let session_key = hkdf(session_key, SALT, INFO);
+        let cipher = ChaCha20Poly1305::new(&session_key.into());
+        if cipher
+            .encrypt_in_place(&Nonce::try_from(NONCE).unwrap(), &[], msg)
+            .is_err()
+        {
+            return Err(ErrorCode::Authentication);
+        }
+
+        Ok(())
+    }
+
     pub fn m5_m6_dec(&self, msg: &mut Vec<u8>) -> Result<(), ErrorCode> {
         const NONCE: &[u8] = b"\0\0\0\0PS-Msg05";
         const SALT: &[u8] = b"Pair-Setup-Encrypt-Salt";
@@ -324,4 +380,41 @@ mod tests {
         assert_eq!(M2, reply.proof());
         assert_eq!(K, session_key);
     }
+
+    #[test]
+    fn test_mfi_proof() {
+        use crate::config::{MfiAuthenticator, SoftwareMfiAuthenticator};
+
+        let authenticator = SoftwareMfiAuthenticator::new([7; 32], b"certificate".to_vec());
+        let mut state = State::new();
+        state.request_mfi(true);
+        state.inner = Inner::Transient {
+            session_key: K.to_vec().into(),
+        };
+
+        let challenge = state.m3_m4_mfi_challenge().unwrap();
+        let signature = authenticator.sign(&challenge);
+        let mut msg = signature.clone();
+        state.m3_m4_enc(&mut msg).unwrap();
+        assert_ne!(msg, signature);
+
+        let key = hkdf(K, b"Pair-Setup-Encrypt-Salt", b"Pair-Setup-Encrypt-Info");
+        ChaCha20Poly1305::new(&key.into())
+            .decrypt_in_place(
+                &Nonce::try_from(&b"\0\0\0\0PS-Msg04"[..]).unwrap(),
+                &[],
+                &mut msg,
+            )
+            .unwrap();
+        assert_eq!(msg, signature);
+
+        let signature = Signature::from_slice(&signature).unwrap();
+        assert!(
+            authenticator
+                .verifying_key()
+                .verify_strict(&challenge, &signature)
+                .is_ok()
+        );
+        assert!(state.mfi_requested());
+    }
 }
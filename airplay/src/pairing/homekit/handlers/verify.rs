@@ -3,7 +3,7 @@ use rand::CryptoRng;
 use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
 
 use super::super::dto::ErrorCode;
-use crate::crypto::hkdf;
+use crate::crypto::{Secret, hkdf};
 
 enum Inner {
     Init,
@@ -108,7 +108,7 @@ impl State {
         device_id: &[u8],
         device_signature: &[u8],
         verify: F,
-    ) -> Result<[u8; 32], ErrorCode>
+    ) -> Result<Secret<[u8; 32]>, ErrorCode>
     where
         F: FnOnce(&[u8], &[u8]) -> bool,
     {
@@ -132,6 +132,6 @@ impl State {
             return Err(ErrorCode::Authentication);
         }
 
-        Ok(shared_secret.to_bytes())
+        Ok(shared_secret.to_bytes().into())
     }
 }
@@ -1,10 +1,16 @@
-use std::sync::Arc;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
 
-use axum::{Extension, Router, routing::post};
+use axum::{Extension, Router, middleware, routing::post};
 use yoke::{Yoke, erased::ErasedArcCart};
 
+pub use self::throttle::{Throttle, Throttled};
 use super::SharedSessionKey;
-use crate::config::{Keychain, PinCode};
+use crate::config::{
+    AccessPolicy, Keychain, MfiAuthenticator, PairingApproval, PairingMode, PinCode, PinDisplay,
+};
 
 pub mod codec;
 
@@ -12,23 +18,44 @@ mod dto;
 mod extractor;
 mod handlers;
 mod state;
+mod throttle;
+
+/// Receiver-wide settings of pairing, the same for every connection.
+#[derive(Clone)]
+pub struct Settings {
+    pub mode: PairingMode,
+    pub pin: Option<PinCode>,
+    pub pin_display: Option<Arc<dyn PinDisplay>>,
+    pub mfi: Option<Arc<dyn MfiAuthenticator>>,
+    pub approval: Option<Arc<dyn PairingApproval>>,
+    pub access: AccessPolicy,
+    pub throttle: Arc<Throttle>,
+}
 
 pub fn router<K>(
     keychain: Yoke<&'static K, ErasedArcCart>,
     session_key: SharedSessionKey,
-    pin: Option<PinCode>,
+    remote_addr: SocketAddr,
+    settings: Settings,
 ) -> Router<()>
 where
     K: Keychain,
 {
-    let state = Arc::new(state::ServiceState::new(pin));
+    let state = Arc::new(state::ServiceState::new(remote_addr, settings));
     Router::new()
         .route("/pair-setup", post(handlers::pair_setup::<K>))
         .route("/pair-verify", post(handlers::pair_verify::<K>))
         // .route("/pair-list", post(()))
         // .route("/pair-add", post(()))
-        // .route("/pair-pin-start", post(()))
+        .route(
+            "/pair-pin-start",
+            post(super::pin::pair_pin_start::<state::ServiceState>),
+        )
         .with_state(state)
+        .layer(middleware::from_fn_with_state(
+            Arc::new(Mutex::new(extractor::fragment::Fragments::default())),
+            extractor::fragment::middleware,
+        ))
         .layer(Extension(keychain))
         .layer(Extension(session_key))
 }
@@ -1,18 +1,57 @@
-use std::sync::Mutex;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
 
-use super::handlers::{setup::State as SetupState, verify::State as VerifyState};
-use crate::config::PinCode;
+use super::{
+    super::pin::PinState,
+    Settings,
+    handlers::{setup::State as SetupState, verify::State as VerifyState},
+    throttle::Throttle,
+};
+use crate::config::{AccessPolicy, MfiAuthenticator, PairingApproval, PairingMode, PinCode};
 
 pub struct ServiceState {
     pub setup_state: Mutex<SetupState>,
     pub verify_state: Mutex<VerifyState>,
+    pub remote_addr: SocketAddr,
+    pub mode: PairingMode,
+    pub pin: PinState,
+    pub throttle: Arc<Throttle>,
+    pub mfi: Option<Arc<dyn MfiAuthenticator>>,
+    pub approval: Option<Arc<dyn PairingApproval>>,
+    pub access: AccessPolicy,
 }
 
 impl ServiceState {
-    pub fn new(pin: Option<PinCode>) -> Self {
+    pub fn new(
+        remote_addr: SocketAddr,
+        Settings {
+            mode,
+            pin,
+            pin_display,
+            mfi,
+            approval,
+            access,
+            throttle,
+        }: Settings,
+    ) -> Self {
         Self {
-            setup_state: Mutex::new(SetupState::new(pin)),
+            setup_state: Mutex::new(SetupState::new()),
             verify_state: Mutex::new(VerifyState::new()),
+            remote_addr,
+            mode,
+            pin: PinState::new(pin, pin_display, || PinCode::random(rand::rng())),
+            throttle,
+            mfi,
+            approval,
+            access,
         }
     }
 }
+
+impl AsRef<PinState> for ServiceState {
+    fn as_ref(&self) -> &PinState {
+        &self.pin
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::PairingLimits;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Throttled {
+    /// Too early for the next attempt, wait for the given time
+    Backoff(Duration),
+    /// No attempts left until the receiver is restarted
+    MaxTries,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    failures: u32,
+    /// Attempts in progress, they may fail too
+    pending: u32,
+    next_attempt: Option<Instant>,
+}
+
+/// Counts failed pair-setup attempts of the receiver, shared between all connections.
+#[derive(Debug)]
+pub struct Throttle {
+    limits: PairingLimits,
+    inner: Mutex<Inner>,
+}
+
+impl Throttle {
+    pub fn new(limits: PairingLimits) -> Self {
+        Self {
+            limits,
+            inner: Mutex::default(),
+        }
+    }
+
+    /// Reserves an attempt, so parallel connections can't try more than `max_tries` in total.
+    pub fn check(&self) -> Result<Attempt<'_>, Throttled> {
+        self.check_at(Instant::now())
+    }
+
+    fn check_at(&self, now: Instant) -> Result<Attempt<'_>, Throttled> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.failures.saturating_add(inner.pending) >= self.limits.max_tries {
+            return Err(Throttled::MaxTries);
+        }
+        if let Some(next_attempt) = inner.next_attempt
+            && next_attempt > now
+        {
+            return Err(Throttled::Backoff(next_attempt - now));
+        }
+
+        inner.pending += 1;
+        Ok(Attempt { throttle: self })
+    }
+
+    fn failure_at(&self, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.failures = inner.failures.saturating_add(1);
+
+        let Some(exp) = inner.failures.checked_sub(self.limits.backoff_after) else {
+            return;
+        };
+        let delay = self
+            .limits
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(exp))
+            .min(self.limits.max_delay);
+
+        inner.next_attempt = Some(now + delay);
+        tracing::warn!(failures = inner.failures, ?delay, "pair-setup failed");
+    }
+}
+
+/// Attempt reserved by [`Throttle::check`], it's given back on drop if nothing is recorded.
+#[derive(Debug)]
+pub struct Attempt<'a> {
+    throttle: &'a Throttle,
+}
+
+impl Attempt<'_> {
+    pub fn failure(self) {
+        self.throttle.failure_at(Instant::now());
+    }
+
+    /// Resets failures of all connections.
+    pub fn success(self) {
+        let mut inner = self.throttle.inner.lock().unwrap();
+        inner.failures = 0;
+        inner.next_attempt = None;
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        self.throttle.inner.lock().unwrap().pending -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Throttle, Throttled};
+    use crate::config::PairingLimits;
+
+    const LIMITS: PairingLimits = PairingLimits {
+        max_tries: 6,
+        backoff_after: 2,
+        base_delay: Duration::from_secs(1),
+        max_delay: Duration::from_secs(5),
+    };
+
+    #[test]
+    fn exponential_backoff() {
+        let throttle = Throttle::new(LIMITS);
+        let now = Instant::now();
+
+        throttle.failure_at(now);
+        assert_eq!(throttle.check_at(now).err(), None);
+
+        throttle.failure_at(now);
+        assert_eq!(
+            throttle.check_at(now).err(),
+            Some(Throttled::Backoff(Duration::from_secs(1)))
+        );
+        assert_eq!(throttle.check_at(now + Duration::from_secs(1)).err(), None);
+
+        throttle.failure_at(now);
+        assert_eq!(
+            throttle.check_at(now).err(),
+            Some(Throttled::Backoff(Duration::from_secs(2)))
+        );
+
+        throttle.failure_at(now);
+        throttle.failure_at(now);
+        assert_eq!(
+            throttle.check_at(now).err(),
+            Some(Throttled::Backoff(LIMITS.max_delay))
+        );
+    }
+
+    #[test]
+    fn lockout_and_reset() {
+        let throttle = Throttle::new(LIMITS);
+        let now = Instant::now();
+
+        for _ in 0..LIMITS.max_tries {
+            throttle.failure_at(now);
+        }
+        assert_eq!(
+            throttle.check_at(now + Duration::from_secs(3600)).err(),
+            Some(Throttled::MaxTries)
+        );
+
+        let throttle = Throttle::new(LIMITS);
+        throttle.failure_at(now);
+        throttle.failure_at(now);
+        throttle.check_at(now + LIMITS.max_delay).unwrap().success();
+        assert_eq!(throttle.check_at(now).err(), None);
+    }
+
+    #[test]
+    fn parallel_attempts_are_counted() {
+        let throttle = Throttle::new(LIMITS);
+        let now = Instant::now();
+
+        let attempts: Vec<_> = (0..LIMITS.max_tries)
+            .map(|_| throttle.check_at(now).unwrap())
+            .collect();
+        assert_eq!(throttle.check_at(now).err(), Some(Throttled::MaxTries));
+
+        // Attempt which ended without a verdict is given back
+        drop(attempts);
+        assert_eq!(throttle.check_at(now).err(), None);
+    }
+}
@@ -0,0 +1,37 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum PinSetupRequest {
+    Start {
+        method: String,
+        user: String,
+    },
+    Verify {
+        pk: Bytes,
+        proof: Bytes,
+    },
+    KeyExchange {
+        epk: Bytes,
+        #[serde(rename = "authTag")]
+        auth_tag: Bytes,
+    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum PinSetupResponse {
+    Start {
+        pk: Bytes,
+        salt: Bytes,
+    },
+    Verify {
+        proof: Bytes,
+    },
+    KeyExchange {
+        epk: Bytes,
+        #[serde(rename = "authTag")]
+        auth_tag: Bytes,
+    },
+}
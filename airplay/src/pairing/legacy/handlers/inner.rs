@@ -1,22 +1,24 @@
 use std::mem;
 
 use aes::cipher::{KeyIvInit as _, StreamCipher};
-use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use ed25519_dalek::{Signature, VerifyingKey};
 use rand::CryptoRng;
 use thiserror::Error;
 use x25519_dalek::{EphemeralSecret, PublicKey};
 
-use crate::crypto::sha512_two_step;
+use crate::crypto::{Secret, sha512_two_step};
 
 pub const X25519_KEY_LEN: usize = 32;
 pub const SIGNATURE_LENGTH: usize = 64;
 
-type SharedSecret = [u8; 32];
+type SharedSecret = Secret<[u8; 32]>;
 type Response = [u8; X25519_KEY_LEN + SIGNATURE_LENGTH];
 type AesCtr128BE = ctr::Ctr128BE<aes::Aes128>;
 
 #[allow(clippy::large_enum_variant)]
+#[derive(Default)]
 enum Inner {
+    #[default]
     Init,
     Established {
         verify_their: VerifyingKey,
@@ -26,30 +28,20 @@ enum Inner {
     },
 }
 
+#[derive(Default)]
 pub struct State {
     state: Inner,
-    signing_our: SigningKey,
 }
 
 impl State {
-    pub fn from_signing_privkey(privkey: &[u8]) -> Self {
-        let privkey = <[u8; _]>::try_from(privkey).expect("32 byte key");
-        Self {
-            state: Inner::Init,
-            signing_our: SigningKey::from_bytes(&privkey),
-        }
-    }
-
-    pub fn verifying_key(&self) -> Vec<u8> {
-        self.signing_our.verifying_key().as_bytes().to_vec()
-    }
-
+    /// `sign` signs the message with our Ed25519 key, the keychain holds it.
     pub fn establish_agreement<R>(
         &mut self,
         mut rand: R,
         pubkey_their: &[u8],
         verify_their: &[u8],
-    ) -> Result<(Response, SharedSecret), Error>
+        sign: impl FnOnce(&[u8]) -> Vec<u8>,
+    ) -> Result<Response, Error>
     where
         R: CryptoRng,
     {
@@ -66,17 +58,18 @@ impl State {
 
         let ephemeral = EphemeralSecret::random_from_rng(&mut rand);
         let pubkey_our = PublicKey::from(&ephemeral);
-        let shared_secret = ephemeral.diffie_hellman(&pubkey_their).to_bytes();
+        let shared_secret = SharedSecret::from(ephemeral.diffie_hellman(&pubkey_their).to_bytes());
 
         let mut signature = {
             let mut buf = [0u8; 2 * X25519_KEY_LEN];
             buf[..X25519_KEY_LEN].copy_from_slice(pubkey_our.as_ref());
             buf[X25519_KEY_LEN..].copy_from_slice(pubkey_their.as_ref());
 
-            self.signing_our.sign(&buf).to_bytes()
+            <[u8; SIGNATURE_LENGTH]>::try_from(sign(&buf))
+                .map_err(|_| Error::Cryptography("invalid signature length"))?
         };
 
-        let mut cipher = cipher(&shared_secret);
+        let mut cipher = cipher(shared_secret.as_slice());
         cipher.apply_keystream(&mut signature);
 
         let mut response = [0u8; X25519_KEY_LEN + SIGNATURE_LENGTH];
@@ -90,10 +83,16 @@ impl State {
             shared_secret,
         };
 
-        Ok((response, shared_secret))
+        Ok(response)
     }
 
-    pub fn verify_agreement(&mut self, mut signature: [u8; SIGNATURE_LENGTH]) -> Result<(), Error> {
+    /// `trusted` decides whether the sender is known, it's given sender's key, the message and
+    /// its signature. Shared secret is given away only if the sender is verified.
+    pub fn verify_agreement(
+        &mut self,
+        mut signature: [u8; SIGNATURE_LENGTH],
+        trusted: impl FnOnce(&[u8], &[u8], &[u8]) -> bool,
+    ) -> Result<SharedSecret, Error> {
         let Inner::Established {
             verify_their,
             pubkey_their,
@@ -104,7 +103,7 @@ impl State {
             return Err(Error::WrongState);
         };
 
-        let mut cipher = cipher(&shared_secret);
+        let mut cipher = cipher(shared_secret.as_slice());
         cipher.apply_keystream(&mut [0u8; SIGNATURE_LENGTH]);
         cipher.apply_keystream(&mut signature);
 
@@ -114,10 +113,12 @@ impl State {
 
         verify_their
             .verify_strict(&message, &Signature::from_bytes(&signature))
-            .map_err(|_| Error::Verification)
-            .inspect(|()| {
-                self.state = Inner::Init;
-            })
+            .map_err(|_| Error::Verification)?;
+        if !trusted(verify_their.as_bytes(), &message, &signature) {
+            return Err(Error::Untrusted);
+        }
+
+        Ok(shared_secret)
     }
 }
 
@@ -130,6 +131,8 @@ pub enum Error {
     Cryptography(&'static str),
     #[error("signature verification")]
     Verification,
+    #[error("unknown sender")]
+    Untrusted,
 }
 
 fn cipher(shared_secret: &[u8]) -> AesCtr128BE {
@@ -141,22 +144,9 @@ fn cipher(shared_secret: &[u8]) -> AesCtr128BE {
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-
-    #[test]
-    fn recreate_from_privkey_seed() {
-        const PRIVKEY: [u8; 32] = [
-            153, 62, 61, 195, 68, 210, 33, 179, 119, 105, 98, 195, 181, 225, 238, 146, 135, 226,
-            224, 74, 233, 172, 222, 140, 80, 52, 153, 66, 147, 209, 98, 170,
-        ];
-        const EXPECTED_ED25519_PUBKEY: [u8; 32] = [
-            63, 87, 112, 234, 30, 34, 240, 218, 63, 236, 178, 92, 117, 7, 156, 75, 162, 206, 30,
-            66, 95, 192, 248, 148, 39, 50, 209, 206, 19, 44, 105, 205,
-        ];
+    use ed25519_dalek::{Signer, SigningKey};
 
-        let state = super::State::from_signing_privkey(&PRIVKEY);
-        assert_eq!(EXPECTED_ED25519_PUBKEY, state.verifying_key().as_slice());
-    }
+    use super::*;
 
     #[test]
     fn test_aes_cipher() {
@@ -173,4 +163,73 @@ mod tests {
 
         assert_eq!(expected, text);
     }
+
+    fn client_signature(
+        signing: &SigningKey,
+        shared_secret: &[u8],
+        pubkey_client: &PublicKey,
+        pubkey_server: &[u8],
+    ) -> [u8; SIGNATURE_LENGTH] {
+        let mut message = [0u8; 2 * X25519_KEY_LEN];
+        message[..X25519_KEY_LEN].copy_from_slice(pubkey_client.as_ref());
+        message[X25519_KEY_LEN..].copy_from_slice(pubkey_server);
+        let mut signature = signing.sign(&message).to_bytes();
+
+        let mut cipher = cipher(shared_secret);
+        cipher.apply_keystream(&mut [0u8; SIGNATURE_LENGTH]);
+        cipher.apply_keystream(&mut signature);
+        signature
+    }
+
+    #[test]
+    fn shared_secret_after_verification() {
+        let mut state = State::default();
+        let signing_our = SigningKey::from_bytes(&[1; 32]);
+        let signing = SigningKey::from_bytes(&[2; 32]);
+
+        for (trusted, corrupt) in [(true, false), (false, false), (true, true)] {
+            let ephemeral = EphemeralSecret::random_from_rng(&mut rand::rng());
+            let pubkey = PublicKey::from(&ephemeral);
+            let response = state
+                .establish_agreement(
+                    rand::rng(),
+                    pubkey.as_bytes(),
+                    signing.verifying_key().as_bytes(),
+                    |message| signing_our.sign(message).to_bytes().to_vec(),
+                )
+                .unwrap();
+
+            let pubkey_server = &response[..X25519_KEY_LEN];
+            let server = PublicKey::from(<[u8; 32]>::try_from(pubkey_server).unwrap());
+            let shared_secret = ephemeral.diffie_hellman(&server).to_bytes();
+
+            // Server signs its key followed by ours with the keychain's key
+            let mut server_signature =
+                <[u8; SIGNATURE_LENGTH]>::try_from(&response[X25519_KEY_LEN..]).unwrap();
+            cipher(&shared_secret).apply_keystream(&mut server_signature);
+            let mut message = [0u8; 2 * X25519_KEY_LEN];
+            message[..X25519_KEY_LEN].copy_from_slice(pubkey_server);
+            message[X25519_KEY_LEN..].copy_from_slice(pubkey.as_bytes());
+            signing_our
+                .verifying_key()
+                .verify_strict(&message, &Signature::from_bytes(&server_signature))
+                .unwrap();
+            let mut signature = client_signature(&signing, &shared_secret, &pubkey, pubkey_server);
+            if corrupt {
+                signature[0] ^= 1;
+            }
+
+            let result = state.verify_agreement(signature, |_, _, _| trusted);
+            match (trusted, corrupt) {
+                (true, false) => assert_eq!(result.unwrap().as_slice(), &shared_secret),
+                (false, _) => assert!(matches!(result, Err(Error::Untrusted))),
+                (true, true) => assert!(matches!(result, Err(Error::Verification))),
+            }
+            // Agreement is used once whatever the result is
+            assert!(matches!(
+                state.verify_agreement(signature, |_, _, _| true),
+                Err(Error::WrongState)
+            ));
+        }
+    }
 }
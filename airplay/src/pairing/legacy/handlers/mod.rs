@@ -2,29 +2,45 @@ use std::sync::Arc;
 
 use axum::{Extension, extract::State, response::IntoResponse};
 use bytes::Bytes;
-use http::StatusCode;
+use http::{StatusCode, header::CONTENT_TYPE};
 use inner::{SIGNATURE_LENGTH, X25519_KEY_LEN};
+use yoke::{Yoke, erased::ErasedArcCart};
 
 use super::{
-    super::{SessionKey, SharedSessionKey},
+    super::{SessionKey, SharedSessionKey, homekit::Throttled},
+    dto::{PinSetupRequest, PinSetupResponse},
     state::ServiceState,
 };
+use crate::{
+    config::{Keychain, PinCode},
+    rtsp::{APPLE_BPLIST_MIME, connection_authorization_required},
+};
 
 pub mod inner;
+pub mod pin;
 
 /// Don't really need request body here, because it duplicates signing key of counterparty got in
 /// the second request.
-#[tracing::instrument(level = "DEBUG", ret, skip(state))]
-pub async fn pair_setup(State(state): State<Arc<ServiceState>>) -> impl IntoResponse {
-    state.pairing.lock().unwrap().verifying_key()
+#[tracing::instrument(level = "DEBUG", ret, skip(keychain))]
+pub async fn pair_setup<K>(
+    Extension(keychain): Extension<Yoke<&'static K, ErasedArcCart>>,
+) -> impl IntoResponse
+where
+    K: Keychain,
+{
+    keychain.get().pubkey().to_vec()
 }
 
-#[tracing::instrument(level = "DEBUG", ret, err, skip(state, session_key))]
-pub async fn pair_verify(
+#[tracing::instrument(level = "DEBUG", ret, err, skip(state, keychain, session_key))]
+pub async fn pair_verify<K>(
     State(state): State<Arc<ServiceState>>,
+    Extension(keychain): Extension<Yoke<&'static K, ErasedArcCart>>,
     Extension(session_key): Extension<SharedSessionKey>,
     body: Bytes,
-) -> Result<impl IntoResponse, StatusCode> {
+) -> Result<impl IntoResponse, StatusCode>
+where
+    K: Keychain,
+{
     if body.len() < 4 + 2 * X25519_KEY_LEN {
         tracing::error!(len=%body.len(), "malformed data for legacy pairing");
         return Err(StatusCode::BAD_REQUEST);
@@ -36,25 +52,141 @@ pub async fn pair_verify(
         let pubkey_their = &body[4..][..X25519_KEY_LEN];
         let verify_their = &body[36..][..X25519_KEY_LEN];
 
+        // Session key is installed only once the sender is verified below
         pairing_state
-            .establish_agreement(rand::rng(), pubkey_their, verify_their)
-            .inspect(|&(_, shared_secret)| {
-                tracing::info!("agreement established");
-                session_key.lock_write().replace(SessionKey {
-                    key_material: shared_secret,
-                    upgrade_channel: false,
-                });
+            .establish_agreement(rand::rng(), pubkey_their, verify_their, |message| {
+                keychain.get().sign(message)
             })
+            .inspect(|_| tracing::info!("agreement established"))
             .inspect_err(|err| tracing::error!(%err, "establishing agreement failed"))
-            .map(|(response, _)| response.into_response())
+            .map(|response| response.into_response())
             .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
     } else {
         let signature = body[4..][..SIGNATURE_LENGTH].try_into().unwrap();
+        let pin_required = state.pin.required();
         pairing_state
-            .verify_agreement(signature)
-            .inspect(|()| tracing::info!("agreement verified"))
-            .inspect_err(|err| tracing::warn!(%err, "agreement verification failed"))
-            .map(|()| ().into_response())
-            .map_err(|_| StatusCode::OK)
+            .verify_agreement(signature, |key, message, signature| {
+                // Senders paired with PIN are saved by their key, see `pair_setup_pin`
+                !pin_required || keychain.get().verify(key, message, signature)
+            })
+            .map(|shared_secret| {
+                tracing::info!("agreement verified");
+                session_key.replace(SessionKey {
+                    key_material: shared_secret.to_vec().into(),
+                    upgrade_channel: false,
+                    pairing_id: None,
+                });
+                ().into_response()
+            })
+            .map_err(|err| {
+                tracing::warn!(%err, "agreement verification failed");
+                match err {
+                    inner::Error::Untrusted => connection_authorization_required(),
+                    _ => StatusCode::FORBIDDEN,
+                }
+            })
     }
 }
+
+#[tracing::instrument(level = "DEBUG", err, skip(state, keychain, body))]
+pub async fn pair_setup_pin<K>(
+    State(state): State<Arc<ServiceState>>,
+    Extension(keychain): Extension<Yoke<&'static K, ErasedArcCart>>,
+    body: Bytes,
+) -> Result<impl IntoResponse, StatusCode>
+where
+    K: Keychain,
+{
+    let request: PinSetupRequest = plist::from_bytes(&body).map_err(|err| {
+        tracing::error!(%err, "malformed pin pairing request");
+        StatusCode::BAD_REQUEST
+    })?;
+
+    let mut pin_setup = state.pin_setup.lock().unwrap();
+    let response = match request {
+        PinSetupRequest::Start { method, user } => {
+            if method != "pin" {
+                tracing::error!(%method, "unknown pairing method");
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            let Some(pin) = state.pin.current() else {
+                tracing::error!("PIN wasn't shown");
+                return Err(StatusCode::FORBIDDEN);
+            };
+
+            let (pk, salt) = pin_setup.start(rand::rng(), &user, &password(pin));
+            PinSetupResponse::Start {
+                pk: pk.into(),
+                salt: salt.into(),
+            }
+        }
+        PinSetupRequest::Verify { pk, proof } => {
+            let attempt = match state.throttle.check() {
+                Ok(attempt) => attempt,
+                Err(Throttled::Backoff(delay)) => {
+                    tracing::warn!(?delay, "pin pairing throttled");
+                    return Err(StatusCode::SERVICE_UNAVAILABLE);
+                }
+                Err(Throttled::MaxTries) => {
+                    tracing::warn!("pin pairing locked, too many failed attempts");
+                    return Err(StatusCode::FORBIDDEN);
+                }
+            };
+
+            let proof = match pin_setup.verify(&pk, &proof) {
+                Ok(proof) => proof,
+                Err(err) => {
+                    tracing::warn!(%err, "wrong PIN");
+                    if matches!(err, inner::Error::Verification) {
+                        attempt.failure();
+                    }
+                    state.pin.hide();
+                    return Err(StatusCode::FORBIDDEN);
+                }
+            };
+            attempt.success();
+
+            PinSetupResponse::Verify {
+                proof: proof.into(),
+            }
+        }
+        PinSetupRequest::KeyExchange { epk, auth_tag } => {
+            // Whatever happens here, pairing is over
+            state.pin.hide();
+
+            let (pubkey_their, epk, auth_tag) = pin_setup
+                .exchange_keys(&epk, &auth_tag, keychain.get().pubkey())
+                .map_err(|err| {
+                    tracing::warn!(%err, "keys exchange failed");
+                    StatusCode::FORBIDDEN
+                })?;
+            // Legacy pair-verify carries no identifier, so the key is the identifier itself
+            if !keychain.get().trust(&pubkey_their, &pubkey_their) {
+                tracing::warn!("keychain refused new sender");
+                return Err(StatusCode::FORBIDDEN);
+            }
+            tracing::info!("sender paired with PIN");
+
+            PinSetupResponse::KeyExchange {
+                epk: epk.into(),
+                auth_tag: auth_tag.into(),
+            }
+        }
+    };
+
+    let mut buf = Vec::new();
+    plist::to_writer_binary(&mut buf, &response).map_err(|err| {
+        tracing::error!(%err, "pin pairing response not serialized");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(([(CONTENT_TYPE, APPLE_BPLIST_MIME)], buf))
+}
+
+/// Legacy senders enter PIN as digits only.
+fn password(pin: PinCode) -> String {
+    pin.digits()
+        .iter()
+        .map(|digit| char::from(b'0' + digit))
+        .collect()
+}
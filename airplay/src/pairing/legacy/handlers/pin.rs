@@ -0,0 +1,399 @@
+//! PIN pairing of AirPlay 1: SRP-6a with SHA-1 and 2048-bit group of RFC 5054, followed by the
+//! exchange of Ed25519 public keys encrypted with the session key.
+
+use std::mem;
+
+use aes_gcm::{
+    AesGcm, Tag,
+    aead::{AeadInOut, KeyInit, consts::U16},
+    aes::Aes128,
+};
+use num_bigint::BigUint;
+use rand::{Rng, RngExt};
+use sha1::{Digest, Sha1};
+use subtle::ConstantTimeEq;
+
+use super::inner::Error;
+use crate::crypto::{Secret, sha512_two_step};
+
+const N_2048: &str = concat!(
+    "AC6BDB41324A9A9BF166DE5E1389582FAF72B6651987EE07FC3192943DB56050",
+    "A37329CBB4A099ED8193E0757767A13DD52312AB4B03310DCD7F48A9DA04FD50",
+    "E8083969EDB767B0CF6095179A163AB3661A05FBD5FAAAE82918A9962F0B93B8",
+    "55F97993EC975EEAA80D740ADBF4FF747359D041D5C33EA71D281E446B14773B",
+    "CA97B43A23FB801676BD207A436C6481F1D2B9078717461A5B9D32E688F87748",
+    "544523B524B0D57D5EA77A2775D2ECFA032CFBDBF52FB3786160279004E57AE6",
+    "AF874E7303CE53299CCC041C7BC308D82A5698F3A8D0C38271AE35F8E9DBFBB6",
+    "94B5C803D89F7AE435DE236D525F54759B65E372FCD68EF20FA7111F9E4AFF73",
+);
+const G: u32 = 2;
+
+const PUBKEY_LEN: usize = 32;
+
+type SessionKey = [u8; 40];
+type Aes128Gcm = AesGcm<Aes128, U16>;
+/// Client's public key, our encrypted public key and its auth tag
+type KeyExchange = ([u8; PUBKEY_LEN], Vec<u8>, Vec<u8>);
+
+#[derive(Default)]
+enum Inner {
+    #[default]
+    Init,
+    Started {
+        user: String,
+        salt: [u8; 16],
+        /// Only byte forms are zeroized, num-bigint can't wipe its digits
+        verifier: Secret<Vec<u8>>,
+        privkey: Secret<[u8; 32]>,
+        pubkey: BigUint,
+    },
+    Verified {
+        session_key: Secret<SessionKey>,
+    },
+}
+
+#[derive(Default)]
+pub struct State {
+    inner: Inner,
+}
+
+impl State {
+    /// Returns server's public key and salt.
+    pub fn start(&mut self, mut rand: impl Rng, user: &str, password: &str) -> (Vec<u8>, Vec<u8>) {
+        let group = Group::rfc5054_2048();
+
+        let salt: [u8; 16] = rand.random();
+        let verifier = group.verifier(user, password, &salt);
+
+        let privkey = Secret::new(rand.random::<[u8; 32]>());
+        let pubkey = group.server_pubkey(&verifier, &BigUint::from_bytes_be(privkey.as_slice()));
+        let verifier = Secret::new(verifier.to_bytes_be());
+
+        let response = (pubkey.to_bytes_be(), salt.to_vec());
+        self.inner = Inner::Started {
+            user: user.to_string(),
+            salt,
+            verifier,
+            privkey,
+            pubkey,
+        };
+
+        response
+    }
+
+    /// Checks client's proof and returns server's one.
+    pub fn verify(&mut self, client_pubkey: &[u8], client_proof: &[u8]) -> Result<Vec<u8>, Error> {
+        let Inner::Started {
+            user,
+            salt,
+            verifier,
+            privkey,
+            pubkey,
+        } = mem::replace(&mut self.inner, Inner::Init)
+        else {
+            return Err(Error::WrongState);
+        };
+        let group = Group::rfc5054_2048();
+
+        let client_pubkey = BigUint::from_bytes_be(client_pubkey);
+        let privkey = BigUint::from_bytes_be(privkey.as_slice());
+        let verifier = BigUint::from_bytes_be(verifier.as_slice());
+        let premaster = group.premaster(&client_pubkey, &pubkey, &verifier, &privkey)?;
+        let premaster = Secret::new(premaster.to_bytes_be());
+        let session_key = Secret::new(session_key(&premaster));
+
+        let proof = group.client_proof(
+            &user,
+            &salt,
+            &client_pubkey,
+            &pubkey,
+            session_key.as_slice(),
+        );
+        if !bool::from(proof[..].ct_eq(client_proof)) {
+            return Err(Error::Verification);
+        }
+
+        let response = sha1(&[&client_pubkey.to_bytes_be(), &proof, session_key.as_slice()]);
+        self.inner = Inner::Verified { session_key };
+
+        Ok(response.to_vec())
+    }
+
+    /// Decrypts client's Ed25519 public key and encrypts our one in response.
+    pub fn exchange_keys(
+        &mut self,
+        epk: &[u8],
+        auth_tag: &[u8],
+        pubkey_our: &[u8],
+    ) -> Result<KeyExchange, Error> {
+        let Inner::Verified { session_key } = mem::replace(&mut self.inner, Inner::Init) else {
+            return Err(Error::WrongState);
+        };
+
+        let Ok(mut pubkey_their) = <[u8; PUBKEY_LEN]>::try_from(epk) else {
+            return Err(Error::Cryptography("invalid encrypted pubkey length"));
+        };
+        let Ok(auth_tag) = Tag::try_from(auth_tag) else {
+            return Err(Error::Cryptography("invalid auth tag length"));
+        };
+
+        let key = sha512_two_step(b"Pair-Setup-AES-Key", session_key.as_slice());
+        let mut iv = sha512_two_step(b"Pair-Setup-AES-IV", session_key.as_slice());
+        let cipher = Aes128Gcm::new(&key.into());
+
+        iv[15] = iv[15].wrapping_add(1);
+        cipher
+            .decrypt_inout_detached(&iv.into(), &[], (&mut pubkey_their[..]).into(), &auth_tag)
+            .map_err(|_| Error::Verification)?;
+
+        iv[15] = iv[15].wrapping_add(1);
+        let mut epk_our = pubkey_our.to_vec();
+        let tag = cipher
+            .encrypt_inout_detached(&iv.into(), &[], epk_our.as_mut_slice().into())
+            .map_err(|_| Error::Cryptography("encryption failed"))?;
+
+        Ok((pubkey_their, epk_our, tag.to_vec()))
+    }
+}
+
+/// Modulus and generator of SRP, senders use only the 2048-bit one.
+struct Group {
+    n: BigUint,
+    g: BigUint,
+}
+
+impl Group {
+    fn rfc5054_2048() -> Self {
+        Self {
+            n: BigUint::parse_bytes(N_2048.as_bytes(), 16).expect("valid hex of SRP group"),
+            g: BigUint::from(G),
+        }
+    }
+
+    /// `v = g^x`, where `x = H(s | H(I | ":" | P))`
+    fn verifier(&self, user: &str, password: &str, salt: &[u8]) -> BigUint {
+        let inner_hash = sha1(&[user.as_bytes(), b":", password.as_bytes()]);
+        let x = BigUint::from_bytes_be(&sha1(&[salt, &inner_hash]));
+        self.g.modpow(&x, &self.n)
+    }
+
+    /// `B = k*v + g^b`
+    fn server_pubkey(&self, verifier: &BigUint, privkey: &BigUint) -> BigUint {
+        let k = BigUint::from_bytes_be(&sha1(&[&self.pad(&self.n), &self.pad(&self.g)]));
+        (k * verifier + self.g.modpow(privkey, &self.n)) % &self.n
+    }
+
+    /// `S = (A * v^u) ^ b`, where `u = H(PAD(A) | PAD(B))`
+    fn premaster(
+        &self,
+        client_pubkey: &BigUint,
+        server_pubkey: &BigUint,
+        verifier: &BigUint,
+        privkey: &BigUint,
+    ) -> Result<BigUint, Error> {
+        if client_pubkey % &self.n == BigUint::ZERO {
+            return Err(Error::Cryptography("invalid SRP public key"));
+        }
+        let u =
+            BigUint::from_bytes_be(&sha1(&[&self.pad(client_pubkey), &self.pad(server_pubkey)]));
+        if u == BigUint::ZERO {
+            return Err(Error::Cryptography("invalid SRP public key"));
+        }
+
+        Ok((client_pubkey * verifier.modpow(&u, &self.n)).modpow(privkey, &self.n))
+    }
+
+    /// `M1 = H(H(N) XOR H(g) | H(I) | s | A | B | K)`
+    fn client_proof(
+        &self,
+        user: &str,
+        salt: &[u8],
+        client_pubkey: &BigUint,
+        server_pubkey: &BigUint,
+        session_key: &[u8],
+    ) -> [u8; 20] {
+        let mut hash_ng = sha1(&[&self.n.to_bytes_be()]);
+        for (x, y) in hash_ng.iter_mut().zip(sha1(&[&self.g.to_bytes_be()])) {
+            *x ^= y;
+        }
+        sha1(&[
+            &hash_ng,
+            &sha1(&[user.as_bytes()]),
+            salt,
+            &client_pubkey.to_bytes_be(),
+            &server_pubkey.to_bytes_be(),
+            session_key,
+        ])
+    }
+
+    fn pad(&self, x: &BigUint) -> Vec<u8> {
+        let len = self.n.bits().div_ceil(8) as usize;
+        let bytes = x.to_bytes_be();
+        let mut padded = vec![0u8; len.saturating_sub(bytes.len())];
+        padded.extend_from_slice(&bytes);
+        padded
+    }
+}
+
+fn sha1(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// Apple's variant, longer than SHA-1 output
+fn session_key(premaster: &[u8]) -> SessionKey {
+    let mut session_key = [0u8; 40];
+    session_key[..20].copy_from_slice(&sha1(&[premaster, &[0, 0, 0, 0]]));
+    session_key[20..].copy_from_slice(&sha1(&[premaster, &[0, 0, 0, 1]]));
+    session_key
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+
+    use super::*;
+
+    /// Client side of the exchange, as it's done by senders
+    fn client(
+        user: &str,
+        password: &str,
+        salt: &[u8],
+        server_pubkey: &[u8],
+    ) -> (Vec<u8>, Vec<u8>, SessionKey) {
+        let group = Group::rfc5054_2048();
+        let (n, g) = (&group.n, &group.g);
+
+        let privkey = BigUint::from_bytes_be(&[7; 32]);
+        let pubkey = g.modpow(&privkey, n);
+        let server_pubkey = BigUint::from_bytes_be(server_pubkey);
+
+        let inner_hash = sha1(&[user.as_bytes(), b":", password.as_bytes()]);
+        let x = BigUint::from_bytes_be(&sha1(&[salt, &inner_hash]));
+        let k = BigUint::from_bytes_be(&sha1(&[&group.pad(n), &group.pad(g)]));
+        let u = BigUint::from_bytes_be(&sha1(&[&group.pad(&pubkey), &group.pad(&server_pubkey)]));
+
+        let base = (&server_pubkey + n * &k - (k * g.modpow(&x, n)) % n) % n;
+        let premaster = base.modpow(&(privkey + u * x), n);
+        let session_key = session_key(&premaster.to_bytes_be());
+        let proof = group.client_proof(user, salt, &pubkey, &server_pubkey, &session_key);
+
+        (pubkey.to_bytes_be(), proof.to_vec(), session_key)
+    }
+
+    #[test]
+    fn pin_pairing() {
+        let mut state = State::default();
+        let (server_pubkey, salt) = state.start(rand::rng(), "366B4165DD64AD3A", "1234");
+
+        let (pubkey, proof, session_key) =
+            client("366B4165DD64AD3A", "1234", &salt, &server_pubkey);
+        let server_proof = state.verify(&pubkey, &proof).unwrap();
+        assert_eq!(
+            server_proof,
+            sha1(&[&pubkey, &proof, &session_key]).as_slice()
+        );
+
+        let key = sha512_two_step(b"Pair-Setup-AES-Key", &session_key);
+        let mut iv = sha512_two_step(b"Pair-Setup-AES-IV", &session_key);
+        let cipher = Aes128Gcm::new(&key.into());
+        iv[15] = iv[15].wrapping_add(1);
+        let mut epk = [1u8; PUBKEY_LEN];
+        let tag = cipher
+            .encrypt_inout_detached(&iv.into(), &[], (&mut epk[..]).into())
+            .unwrap();
+
+        let (pubkey_their, mut epk_our, tag_our) =
+            state.exchange_keys(&epk, &tag, &[2u8; 32]).unwrap();
+        assert_eq!(pubkey_their, [1u8; PUBKEY_LEN]);
+
+        iv[15] = iv[15].wrapping_add(1);
+        cipher
+            .decrypt_inout_detached(
+                &iv.into(),
+                &[],
+                epk_our.as_mut_slice().into(),
+                &Tag::try_from(tag_our.as_slice()).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(epk_our, [2u8; 32]);
+    }
+
+    #[test]
+    fn wrong_password() {
+        let mut state = State::default();
+        let (server_pubkey, salt) = state.start(rand::rng(), "user", "1234");
+
+        let (pubkey, proof, _) = client("user", "4321", &salt, &server_pubkey);
+        assert!(matches!(
+            state.verify(&pubkey, &proof),
+            Err(Error::Verification)
+        ));
+        assert!(matches!(
+            state.exchange_keys(&[0; 32], &[0; 16], &[0; 32]),
+            Err(Error::WrongState)
+        ));
+    }
+
+    #[test]
+    fn rfc5054_test_vector() {
+        fn hex(s: &str) -> BigUint {
+            BigUint::parse_bytes(s.replace(' ', "").as_bytes(), 16).unwrap()
+        }
+
+        // Appendix B uses the 1024-bit group of Appendix A
+        let group = Group {
+            n: hex(concat!(
+                "EEAF0AB9 ADB38DD6 9C33F80A FA8FC5E8 60726187 75FF3C0B 9EA2314C 9C256576 ",
+                "D674DF74 96EA81D3 383B4813 D692C6E0 E0D5D8E2 50B98BE4 8E495C1D 6089DAD1 ",
+                "5DC7D7B4 6154D6B6 CE8EF4AD 69B15D49 82559B29 7BCF1885 C529F566 660E57EC ",
+                "68EDBC3C 05726CC0 2FD4CBF4 976EAA9A FD5138FE 8376435B 9FC61D2F C0EB06E3",
+            )),
+            g: BigUint::from(2u32),
+        };
+        let salt = hex("BEB25379 D1A8581E B5A72767 3A2441EE").to_bytes_be();
+        let client_privkey =
+            hex("60975527 035CF2AD 1989806F 0407210B C81EDC04 E2762A56 AFD529DD DA2D4393");
+        let privkey =
+            hex("E487CB59 D31AC550 471E81F0 0F6928E0 1DDA08E9 74A004F4 9E61F5D1 05284D20");
+
+        let verifier = group.verifier("alice", "password123", &salt);
+        assert_eq!(
+            verifier,
+            hex(concat!(
+                "7E273DE8 696FFC4F 4E337D05 B4B375BE B0DDE156 9E8FA00A 9886D812 9BADA1F1 ",
+                "822223CA 1A605B53 0E379BA4 729FDC59 F105B478 7E5186F5 C671085A 1447B52A ",
+                "48CF1970 B4FB6F84 00BBF4CE BFBB1681 52E08AB5 EA53D15C 1AFF87B2 B9DA6E04 ",
+                "E058AD51 CC72BFC9 033B564E 26480D78 E955A5E2 9E7AB245 DB2BE315 E2099AFB",
+            ))
+        );
+
+        let pubkey = group.server_pubkey(&verifier, &privkey);
+        assert_eq!(
+            pubkey,
+            hex(concat!(
+                "BD0C6151 2C692C0C B6D041FA 01BB152D 4916A1E7 7AF46AE1 05393011 BAF38964 ",
+                "DC46A067 0DD125B9 5A981652 236F99D9 B681CBF8 7837EC99 6C6DA044 53728610 ",
+                "D0C6DDB5 8B318885 D7D82C7F 8DEB75CE 7BD4FBAA 37089E6F 9C6059F3 88838E7A ",
+                "00030B33 1EB76840 910440B1 B27AAEAE EB4012B7 D7665238 A8E3FB00 4B117B58",
+            ))
+        );
+
+        let client_pubkey = group.g.modpow(&client_privkey, &group.n);
+        let premaster = group
+            .premaster(&client_pubkey, &pubkey, &verifier, &privkey)
+            .unwrap();
+        assert_eq!(
+            premaster,
+            hex(concat!(
+                "B0DC82BA BCF30674 AE450C02 87745E79 90A3381F 63B387AA F271A10D 233861E3 ",
+                "59B48220 F7C4693C 9AE12B0A 6F67809F 0876E2D0 13800D6C 41BB59B6 D5979B5C ",
+                "00A172B4 A2A5903A 0BDCAF8A 709585EB 2AFAFA8F 3499B200 210DCC1F 10EB3394 ",
+                "3CD67FC8 8A2F39A4 BE5BEC4E C0A3212D C346D7E4 74B29EDE 8A469FFE CA686E5A",
+            ))
+        );
+    }
+}
@@ -3,22 +3,39 @@ use std::sync::Arc;
 use axum::{Extension, Router, routing::post};
 use yoke::{Yoke, erased::ErasedArcCart};
 
-use super::SharedSessionKey;
-use crate::config::Keychain;
+use super::{SharedSessionKey, homekit::Throttle};
+use crate::config::{Keychain, PinCode, PinDisplay};
 
+mod dto;
 mod handlers;
 mod state;
 
 pub fn router<K>(
     keychain: Yoke<&'static K, ErasedArcCart>,
     session_key: SharedSessionKey,
+    pin: Option<PinCode>,
+    pin_display: Option<Arc<dyn PinDisplay>>,
+    throttle: Arc<Throttle>,
 ) -> Router<()>
 where
     K: Keychain,
 {
-    Router::new()
-        .route("/pair-setup", post(handlers::pair_setup))
-        .route("/pair-verify", post(handlers::pair_verify))
-        .with_state(Arc::new(state::ServiceState::new(keychain.get().pubkey())))
+    let state = Arc::new(state::ServiceState::new(pin, pin_display, throttle));
+
+    let mut router = Router::new()
+        .route("/pair-setup", post(handlers::pair_setup::<K>))
+        .route("/pair-verify", post(handlers::pair_verify::<K>));
+    if state.pin.required() {
+        router = router
+            .route(
+                "/pair-pin-start",
+                post(super::pin::pair_pin_start::<state::ServiceState>),
+            )
+            .route("/pair-setup-pin", post(handlers::pair_setup_pin::<K>));
+    }
+
+    router
+        .with_state(state)
+        .layer(Extension(keychain))
         .layer(Extension(session_key))
 }
@@ -1,15 +1,37 @@
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
-use super::handlers::inner::State as InnerState;
+use super::{
+    super::{homekit::Throttle, pin::PinState},
+    handlers::{inner::State as InnerState, pin::State as PinSetupState},
+};
+use crate::config::{PinCode, PinDisplay};
 
 pub struct ServiceState {
     pub pairing: Mutex<InnerState>,
+    pub pin_setup: Mutex<PinSetupState>,
+    /// Senders must pass PIN pairing before pair-verify if it's required
+    pub pin: PinState,
+    /// Failed PIN attempts, shared by all connections of the receiver
+    pub throttle: Arc<Throttle>,
 }
 
 impl ServiceState {
-    pub fn new(privkey: &[u8]) -> Self {
+    pub fn new(
+        pin: Option<PinCode>,
+        pin_display: Option<Arc<dyn PinDisplay>>,
+        throttle: Arc<Throttle>,
+    ) -> Self {
         Self {
-            pairing: Mutex::new(InnerState::from_signing_privkey(privkey)),
+            pairing: Mutex::default(),
+            pin_setup: Mutex::default(),
+            pin: PinState::new(pin, pin_display, || PinCode::random_legacy(rand::rng())),
+            throttle,
         }
     }
 }
+
+impl AsRef<PinState> for ServiceState {
+    fn as_ref(&self) -> &PinState {
+        &self.pin
+    }
+}
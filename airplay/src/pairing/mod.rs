@@ -1,15 +1,31 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use seqlock::SeqLock;
+use crate::crypto::Secret;
 
 pub mod codec;
 pub mod homekit;
 pub mod legacy;
 
-pub type SharedSessionKey = Arc<SeqLock<Option<SessionKey>>>;
+mod pin;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Default)]
+pub struct SharedSessionKey(Arc<Mutex<Option<SessionKey>>>);
+
+impl SharedSessionKey {
+    pub fn read(&self) -> Option<SessionKey> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn replace(&self, key: SessionKey) -> Option<SessionKey> {
+        self.0.lock().unwrap().replace(key)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct SessionKey {
-    pub key_material: [u8; 32],
+    /// Shared secret of pair-verify or SRP session key of transient pair-setup
+    pub key_material: Secret<Vec<u8>>,
     pub upgrade_channel: bool,
+    /// Identifier of HomeKit controller, known after pair-verify only
+    pub pairing_id: Option<Vec<u8>>,
 }
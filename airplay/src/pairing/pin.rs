@@ -0,0 +1,101 @@
+use std::sync::{Arc, Mutex};
+
+use axum::extract::State;
+
+use crate::config::{PinCode, PinDisplay};
+
+/// PIN senders must enter to pair, either the static one or the last one shown.
+pub struct PinState {
+    fixed: Option<PinCode>,
+    pin: Mutex<Option<PinCode>>,
+    display: Option<Arc<dyn PinDisplay>>,
+    random: fn() -> PinCode,
+}
+
+impl PinState {
+    /// `random` generates a PIN of the length the senders expect.
+    pub fn new(
+        pin: Option<PinCode>,
+        display: Option<Arc<dyn PinDisplay>>,
+        random: fn() -> PinCode,
+    ) -> Self {
+        Self {
+            fixed: pin,
+            pin: Mutex::new(pin),
+            display,
+            random,
+        }
+    }
+
+    pub fn required(&self) -> bool {
+        self.pin.lock().unwrap().is_some() || self.display.is_some()
+    }
+
+    pub fn current(&self) -> Option<PinCode> {
+        *self.pin.lock().unwrap()
+    }
+
+    /// Ends the attempt, the shown PIN is forgotten and the static one is back.
+    pub fn hide(&self) {
+        *self.pin.lock().unwrap() = self.fixed;
+        if let Some(display) = &self.display {
+            display.hide();
+        }
+    }
+}
+
+/// Without [`PinDisplay`] the static PIN is kept, nobody could see a new one.
+pub async fn pair_pin_start<S>(State(state): State<Arc<S>>)
+where
+    S: AsRef<PinState>,
+{
+    let state = (*state).as_ref();
+    if let Some(display) = &state.display {
+        let pin = (state.random)();
+        *state.pin.lock().unwrap() = Some(pin);
+        display.show(pin);
+        tracing::debug!("new pin is shown");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Screen(Mutex<Option<PinCode>>);
+
+    impl PinDisplay for Screen {
+        fn show(&self, pin: PinCode) {
+            *self.0.lock().unwrap() = Some(pin);
+        }
+
+        fn hide(&self) {
+            *self.0.lock().unwrap() = None;
+        }
+    }
+
+    struct Service(PinState);
+
+    impl AsRef<PinState> for Service {
+        fn as_ref(&self) -> &PinState {
+            &self.0
+        }
+    }
+
+    #[tokio::test]
+    async fn random_pin_is_forgotten_after_attempt() {
+        let screen = Arc::new(Screen(Mutex::default()));
+        let random = || PinCode::random(rand::rng());
+        let service = Arc::new(Service(PinState::new(None, Some(screen.clone()), random)));
+
+        pair_pin_start(State(service.clone())).await;
+        let shown = screen.0.lock().unwrap().expect("pin is shown");
+        assert_eq!(service.0.current(), Some(shown));
+
+        // Failed attempt
+        service.0.hide();
+        assert_eq!(*screen.0.lock().unwrap(), None);
+        assert_eq!(service.0.current(), None);
+        assert!(service.0.required());
+    }
+}
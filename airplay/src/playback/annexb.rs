@@ -0,0 +1,414 @@
+//! Conversion of length-prefixed NAL units (as they arrive in [`VideoPacket::payload`]) into the
+//! Annex-B byte stream format expected by most decoders and muxers.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use thiserror::Error;
+
+use super::video::{PacketKind, VideoPacket};
+
+const START_CODE: &[u8] = &[0, 0, 0, 1];
+
+#[derive(Debug, Error)]
+pub enum AnnexBError {
+    #[error("truncated decoder configuration record")]
+    TruncatedConfig,
+    #[error("unsupported configuration version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("NAL unit out of bounds at {0}")]
+    TruncatedNal(usize),
+    #[error("payload received before decoder configuration")]
+    MissingConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    H264,
+    H265,
+}
+
+#[derive(Debug)]
+pub struct AnnexBFrame {
+    pub codec: VideoCodec,
+    pub keyframe: bool,
+    pub timestamp: u64,
+    pub data: BytesMut,
+}
+
+/// Keeps track of the latest parameter sets (SPS/PPS or VPS/SPS/PPS) and converts frames.
+#[derive(Debug, Default)]
+pub struct AnnexBConverter {
+    config: Option<DecoderConfig>,
+}
+
+#[derive(Debug)]
+struct DecoderConfig {
+    codec: VideoCodec,
+    nal_length_size: usize,
+    // Already in Annex-B format
+    parameter_sets: Bytes,
+}
+
+impl AnnexBConverter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn codec(&self) -> Option<VideoCodec> {
+        self.config.as_ref().map(|config| config.codec)
+    }
+
+    /// Parameter sets of the latest configuration, each prefixed with a start code.
+    pub fn parameter_sets(&self) -> Option<&Bytes> {
+        self.config.as_ref().map(|config| &config.parameter_sets)
+    }
+
+    /// Consumes a packet, returning a converted frame for payload packets.
+    ///
+    /// Codec packets only update the internal state, others are ignored.
+    pub fn push(&mut self, packet: VideoPacket) -> Result<Option<AnnexBFrame>, AnnexBError> {
+        match packet.kind {
+            PacketKind::AvcC => {
+                self.config = Some(parse_avcc(&packet.payload)?);
+                Ok(None)
+            }
+            PacketKind::HvcC => {
+                self.config = Some(parse_hvcc(&packet.payload)?);
+                Ok(None)
+            }
+            PacketKind::Payload => self.convert(packet.timestamp, packet.payload).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn convert(&self, timestamp: u64, payload: BytesMut) -> Result<AnnexBFrame, AnnexBError> {
+        let Some(config) = &self.config else {
+            return Err(AnnexBError::MissingConfig);
+        };
+
+        let mut keyframe = false;
+        let mut has_parameter_sets = false;
+        for nal in nal_units(&payload, config.nal_length_size) {
+            let header = *nal?.first().unwrap_or(&0);
+            match config.codec {
+                VideoCodec::H264 => match header & 0x1F {
+                    5 => keyframe = true,
+                    7 | 8 => has_parameter_sets = true,
+                    _ => {}
+                },
+                VideoCodec::H265 => match (header >> 1) & 0x3F {
+                    16..=21 => keyframe = true,
+                    32..=34 => has_parameter_sets = true,
+                    _ => {}
+                },
+            }
+        }
+
+        let prefix = if keyframe && !has_parameter_sets {
+            &config.parameter_sets[..]
+        } else {
+            &[]
+        };
+
+        let data = if config.nal_length_size == START_CODE.len() && prefix.is_empty() {
+            // Same length of prefix, so it's replaced in-place
+            let mut payload = payload;
+            let mut pos = 0;
+            while pos + START_CODE.len() <= payload.len() {
+                let len = (&payload[pos..]).get_u32() as usize;
+                payload[pos..pos + START_CODE.len()].copy_from_slice(START_CODE);
+                pos += START_CODE.len() + len;
+            }
+            payload
+        } else {
+            let mut output = BytesMut::with_capacity(prefix.len() + payload.len() + 64);
+            output.put_slice(prefix);
+            for nal in nal_units(&payload, config.nal_length_size) {
+                output.put_slice(START_CODE);
+                output.put_slice(nal?);
+            }
+            output
+        };
+
+        Ok(AnnexBFrame {
+            codec: config.codec,
+            keyframe,
+            timestamp,
+            data,
+        })
+    }
+}
+
+fn nal_units(
+    mut buf: &[u8],
+    nal_length_size: usize,
+) -> impl Iterator<Item = Result<&[u8], AnnexBError>> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        if buf.is_empty() {
+            return None;
+        }
+        if buf.len() < nal_length_size {
+            buf = &[];
+            return Some(Err(AnnexBError::TruncatedNal(offset)));
+        }
+
+        let len = (&buf[..nal_length_size]).get_uint(nal_length_size) as usize;
+        let Some(nal) = buf.get(nal_length_size..nal_length_size + len) else {
+            buf = &[];
+            return Some(Err(AnnexBError::TruncatedNal(offset)));
+        };
+        buf = &buf[nal_length_size + len..];
+        offset += nal_length_size + len;
+
+        Some(Ok(nal))
+    })
+}
+
+fn parse_avcc(record: &[u8]) -> Result<DecoderConfig, AnnexBError> {
+    let mut buf = record;
+    if buf.remaining() < 6 {
+        return Err(AnnexBError::TruncatedConfig);
+    }
+
+    let version = buf.get_u8();
+    if version != 1 {
+        return Err(AnnexBError::UnsupportedVersion(version));
+    }
+    // Profile, compatibility and level
+    buf.advance(3);
+    let nal_length_size = usize::from(buf.get_u8() & 0x03) + 1;
+
+    let mut parameter_sets = BytesMut::new();
+    let sps_count = buf.get_u8() & 0x1F;
+    read_parameter_sets(&mut buf, sps_count.into(), &mut parameter_sets)?;
+    if !buf.has_remaining() {
+        return Err(AnnexBError::TruncatedConfig);
+    }
+    let pps_count = buf.get_u8();
+    read_parameter_sets(&mut buf, pps_count.into(), &mut parameter_sets)?;
+
+    Ok(DecoderConfig {
+        codec: VideoCodec::H264,
+        nal_length_size,
+        parameter_sets: parameter_sets.freeze(),
+    })
+}
+
+fn parse_hvcc(payload: &[u8]) -> Result<DecoderConfig, AnnexBError> {
+    const HVCC_HEADER_LEN: usize = 23;
+
+    // Senders wrap the record into `hvc1` sample entry, so lookup for the nested box
+    let mut buf = payload
+        .windows(4)
+        .position(|fourcc| fourcc == b"hvcC")
+        .map_or(payload, |pos| &payload[pos + 4..]);
+    if buf.remaining() < HVCC_HEADER_LEN {
+        return Err(AnnexBError::TruncatedConfig);
+    }
+
+    let version = buf[0];
+    if version != 1 {
+        return Err(AnnexBError::UnsupportedVersion(version));
+    }
+    let nal_length_size = usize::from(buf[21] & 0x03) + 1;
+    let arrays = buf[22];
+    buf.advance(HVCC_HEADER_LEN);
+
+    let mut parameter_sets = BytesMut::new();
+    for _ in 0..arrays {
+        if buf.remaining() < 3 {
+            return Err(AnnexBError::TruncatedConfig);
+        }
+        // NAL type, it's already in header of each unit
+        buf.advance(1);
+        let count = buf.get_u16();
+        read_parameter_sets(&mut buf, count.into(), &mut parameter_sets)?;
+    }
+
+    Ok(DecoderConfig {
+        codec: VideoCodec::H265,
+        nal_length_size,
+        parameter_sets: parameter_sets.freeze(),
+    })
+}
+
+fn read_parameter_sets(
+    buf: &mut &[u8],
+    count: usize,
+    output: &mut BytesMut,
+) -> Result<(), AnnexBError> {
+    for _ in 0..count {
+        if buf.remaining() < 2 {
+            return Err(AnnexBError::TruncatedConfig);
+        }
+        let len = usize::from(buf.get_u16());
+        if buf.remaining() < len {
+            return Err(AnnexBError::TruncatedConfig);
+        }
+        output.put_slice(START_CODE);
+        output.put_slice(&buf[..len]);
+        buf.advance(len);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::*;
+
+    const SPS: &[u8] = &[0x67, 0x64, 0x00, 0x1F, 0xAC];
+    const PPS: &[u8] = &[0x68, 0xEE, 0x3C, 0x80];
+
+    fn avcc(nal_length_size: u8) -> VideoPacket {
+        let mut record = vec![1, 0x64, 0x00, 0x1F, 0xFC | (nal_length_size - 1), 0xE1];
+        record.extend_from_slice(&(SPS.len() as u16).to_be_bytes());
+        record.extend_from_slice(SPS);
+        record.push(1);
+        record.extend_from_slice(&(PPS.len() as u16).to_be_bytes());
+        record.extend_from_slice(PPS);
+
+        VideoPacket {
+            kind: PacketKind::AvcC,
+            timestamp: 0,
+            keyframe: false,
+            payload: BytesMut::from(&record[..]),
+        }
+    }
+
+    fn payload(nals: &[&[u8]]) -> VideoPacket {
+        let mut payload = BytesMut::new();
+        for nal in nals {
+            payload.put_u32(nal.len() as u32);
+            payload.put_slice(nal);
+        }
+
+        VideoPacket {
+            kind: PacketKind::Payload,
+            timestamp: 42,
+            keyframe: false,
+            payload,
+        }
+    }
+
+    #[test]
+    fn payload_before_config() {
+        let mut converter = AnnexBConverter::new();
+        assert!(matches!(
+            converter.push(payload(&[&[0x65, 1, 2]])),
+            Err(AnnexBError::MissingConfig)
+        ));
+    }
+
+    #[test]
+    fn keyframe_gets_parameter_sets() {
+        let mut converter = AnnexBConverter::new();
+        assert!(converter.push(avcc(4)).unwrap().is_none());
+        assert_eq!(converter.codec(), Some(VideoCodec::H264));
+
+        let frame = converter
+            .push(payload(&[&[0x65, 1, 2, 3]]))
+            .unwrap()
+            .unwrap();
+        assert!(frame.keyframe);
+        assert_eq!(frame.timestamp, 42);
+
+        let mut expected = Vec::new();
+        expected.extend_from_slice(START_CODE);
+        expected.extend_from_slice(SPS);
+        expected.extend_from_slice(START_CODE);
+        expected.extend_from_slice(PPS);
+        expected.extend_from_slice(START_CODE);
+        expected.extend_from_slice(&[0x65, 1, 2, 3]);
+        assert_eq!(frame.data, expected);
+    }
+
+    #[test]
+    fn non_keyframe_converted_in_place() {
+        let mut converter = AnnexBConverter::new();
+        converter.push(avcc(4)).unwrap();
+
+        let packet = payload(&[&[0x41, 1, 2], &[0x41, 3]]);
+        let ptr = packet.payload.as_ptr();
+        let frame = converter.push(packet).unwrap().unwrap();
+
+        assert!(!frame.keyframe);
+        assert_eq!(frame.data.as_ptr(), ptr);
+        assert_eq!(
+            frame.data,
+            &[0, 0, 0, 1, 0x41, 1, 2, 0, 0, 0, 1, 0x41, 3][..]
+        );
+    }
+
+    #[test]
+    fn short_length_prefix() {
+        let mut converter = AnnexBConverter::new();
+        converter.push(avcc(2)).unwrap();
+
+        let frame = converter
+            .convert(0, BytesMut::from(&[0, 2, 0x41, 7][..]))
+            .unwrap();
+        assert_eq!(frame.data, &[0, 0, 0, 1, 0x41, 7][..]);
+    }
+
+    #[test]
+    fn truncated_nal() {
+        let mut converter = AnnexBConverter::new();
+        converter.push(avcc(4)).unwrap();
+
+        assert!(matches!(
+            converter.convert(0, BytesMut::from(&[0, 0, 0, 9, 0x41][..])),
+            Err(AnnexBError::TruncatedNal(0))
+        ));
+    }
+
+    #[test]
+    fn hvcc_nested_in_sample_entry() {
+        const VPS: &[u8] = &[0x40, 0x01, 0x0C];
+        const SPS: &[u8] = &[0x42, 0x01, 0x01];
+        const PPS: &[u8] = &[0x44, 0x01, 0xC1];
+
+        let mut payload = vec![0, 0, 0, 0];
+        payload.extend_from_slice(b"hvc1");
+        payload.extend_from_slice(&[0; 78]);
+        payload.extend_from_slice(&[0, 0, 0, 0]);
+        payload.extend_from_slice(b"hvcC");
+        let mut header = [0u8; 23];
+        header[0] = 1;
+        header[21] = 0x0F;
+        header[22] = 3;
+        payload.extend_from_slice(&header);
+        for (ty, nal) in [(0xA0, VPS), (0xA1, SPS), (0xA2, PPS)] {
+            payload.push(ty);
+            payload.extend_from_slice(&1u16.to_be_bytes());
+            payload.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+            payload.extend_from_slice(nal);
+        }
+
+        let mut converter = AnnexBConverter::new();
+        converter
+            .push(VideoPacket {
+                kind: PacketKind::HvcC,
+                timestamp: 0,
+                keyframe: false,
+                payload: BytesMut::from(&payload[..]),
+            })
+            .unwrap();
+        assert_eq!(converter.codec(), Some(VideoCodec::H265));
+
+        // IDR_W_RADL
+        let frame = converter
+            .convert(0, BytesMut::from(&[0, 0, 0, 2, 0x26, 0x01][..]))
+            .unwrap();
+        assert!(frame.keyframe);
+        assert_eq!(
+            frame.data,
+            &[
+                0, 0, 0, 1, 0x40, 0x01, 0x0C, 0, 0, 0, 1, 0x42, 0x01, 0x01, 0, 0, 0, 1, 0x44, 0x01,
+                0xC1, 0, 0, 0, 1, 0x26, 0x01
+            ][..]
+        );
+    }
+}
@@ -1,5 +1,8 @@
 use std::{error::Error, future::Future, sync::Weak};
 
+use plist::{Dictionary, Value};
+
+pub mod annexb;
 pub mod audio;
 pub mod null;
 pub mod video;
@@ -19,6 +22,39 @@ pub trait Device: Send + Sync + 'static {
 
 pub trait ChannelHandle: Send + Sync + 'static {
     fn close(&self);
+
+    /// Queues an event for the sender, `false` if it's dropped (e.g. there's no event channel).
+    fn send_event(&self, event: Event) -> bool {
+        tracing::debug!(?event, "event channel isn't supported");
+        false
+    }
+}
+
+/// Notification pushed to the sender through the event channel, as `POST /command`.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum Event {
+    /// Fields of `/info` that changed, e.g. volume or name
+    UpdateInfo(Dictionary),
+    /// Command of any other type, e.g. playback state
+    Command { ty: String, value: Option<Value> },
+}
+
+impl Event {
+    /// Body of the command, binary plist is built from it.
+    pub fn to_plist(&self) -> Dictionary {
+        let (ty, value) = match self {
+            Self::UpdateInfo(info) => ("updateInfo", Some(Value::Dictionary(info.clone()))),
+            Self::Command { ty, value } => (ty.as_str(), value.clone()),
+        };
+
+        let mut dict = Dictionary::new();
+        dict.insert("type".to_string(), Value::String(ty.to_string()));
+        if let Some(value) = value {
+            dict.insert("value".to_string(), value);
+        }
+        dict
+    }
 }
 
 pub trait Stream: Send + Sync + 'static {
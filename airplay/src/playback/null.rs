@@ -5,7 +5,7 @@ use std::{
 use super::{
     ChannelHandle, Device, Stream,
     audio::{AudioDevice, AudioPacket, AudioParams},
-    video::{VideoDevice, VideoPacket, VideoParams},
+    video::{VideoDevice, VideoPacket, VideoParams, VideoStream},
 };
 
 pub struct NullDevice<Params, Content>(PhantomData<(Params, Content)>);
@@ -54,6 +54,8 @@ impl VideoDevice for NullDevice<VideoParams, VideoPacket> {}
 
 pub struct NullStream<C>(PhantomData<C>);
 
+impl VideoStream for NullStream<VideoPacket> {}
+
 unsafe impl<C> Send for NullStream<C> {}
 unsafe impl<C> Sync for NullStream<C> {}
 
@@ -1,28 +1,208 @@
+use std::time::Duration;
+
 use bytes::BytesMut;
+use plist::{Dictionary, Value};
 
 use super::{Device, Stream};
 
 pub trait VideoDevice: Device<Params = VideoParams, Stream: VideoStream> {}
 
-pub trait VideoStream: Stream<Content = VideoPacket> {}
-impl<T> VideoStream for T where T: Stream<Content = VideoPacket> {}
+pub trait VideoStream: Stream<Content = VideoPacket> {
+    /// Called for every plist packet of the stream that was parsed successfully.
+    fn on_event(&self, event: MirroringEvent) {
+        tracing::trace!(?event, "mirroring event skipped");
+    }
+}
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 #[non_exhaustive]
-pub struct VideoParams {}
+pub struct VideoParams {
+    /// Latency budget requested by sender
+    pub latency: Duration,
+    /// Whether the stream mirrors sender's screen rather than plays a video
+    pub screen_mirroring: bool,
+    /// Identifier of the display profile sender picked for this stream
+    pub display_uuid: Option<String>,
+    /// Names of timestamps sender will report, see [`MirroringEvent::StreamMetadata`]
+    pub timestamp_info: Vec<String>,
+}
 
 #[derive(Debug)]
 pub struct VideoPacket {
     pub kind: PacketKind,
     pub timestamp: u64,
+    /// Sender flagged the frame as a keyframe, decoding may start from it
+    pub keyframe: bool,
     pub payload: BytesMut,
 }
 
+impl VideoPacket {
+    /// Converts 32.32 fixed point NTP timestamp to duration since sender's NTP epoch.
+    pub fn ntp_time(&self) -> Duration {
+        let secs = self.timestamp >> 32;
+        let nanos = ((self.timestamp & 0xFFFF_FFFF) * 1_000_000_000) >> 32;
+        Duration::new(secs, nanos as u32)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum PacketKind {
     AvcC,
     HvcC,
     Payload,
     Plist,
+    /// Keepalive, never passed to [`VideoStream`]
+    Heartbeat,
+    /// Raw packet type from the header
     Other(u16),
 }
+
+/// Typed content of [`PacketKind::Plist`] packets sent by the mirroring sender
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum MirroringEvent {
+    /// Resolution of sender's display has changed, new codec packet usually follows
+    DisplayConfig {
+        width: f64,
+        height: f64,
+        refresh_rate: Option<f64>,
+    },
+    /// Sender's screen has been rotated
+    Rotation { degrees: i64 },
+    /// Names of timestamps the sender reports for stages of its pipeline
+    StreamMetadata { timestamp_info: Vec<String> },
+    /// Message with unknown set of keys
+    Other(Dictionary),
+}
+
+impl MirroringEvent {
+    pub fn from_plist(buf: &[u8]) -> Result<Self, plist::Error> {
+        let dict: Dictionary = plist::from_bytes(buf)?;
+
+        let number = |key: &str| {
+            dict.get(key).and_then(|value| {
+                value
+                    .as_real()
+                    .or_else(|| value.as_signed_integer().map(|x| x as f64))
+            })
+        };
+
+        if let Some(Value::Array(info)) = dict.get("timestampInfo") {
+            let timestamp_info = info
+                .iter()
+                .filter_map(|entry| {
+                    entry
+                        .as_dictionary()?
+                        .get("name")?
+                        .as_string()
+                        .map(str::to_string)
+                })
+                .collect();
+
+            Ok(Self::StreamMetadata { timestamp_info })
+        } else if let Some(degrees) = dict
+            .get("rotation")
+            .or_else(|| dict.get("orientation"))
+            .and_then(Value::as_signed_integer)
+        {
+            Ok(Self::Rotation { degrees })
+        } else if let Some(width) = number("width").or_else(|| number("widthPixels"))
+            && let Some(height) = number("height").or_else(|| number("heightPixels"))
+        {
+            Ok(Self::DisplayConfig {
+                width,
+                height,
+                refresh_rate: number("refreshRate").or_else(|| number("maxFPS")),
+            })
+        } else {
+            Ok(Self::Other(dict))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::BytesMut;
+    use plist::{Dictionary, Value};
+
+    use super::{MirroringEvent, PacketKind, VideoPacket};
+
+    #[test]
+    fn ntp_time() {
+        let pkt = VideoPacket {
+            kind: PacketKind::Payload,
+            timestamp: 0x0c2f_8000_0000,
+            keyframe: false,
+            payload: BytesMut::new(),
+        };
+        assert_eq!(pkt.ntp_time(), Duration::from_millis(3_119_500));
+    }
+
+    fn bplist(entries: impl IntoIterator<Item = (&'static str, Value)>) -> Vec<u8> {
+        let dict = entries
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect::<Dictionary>();
+        let mut buf = Vec::new();
+        plist::to_writer_binary(&mut buf, &dict).unwrap();
+        buf
+    }
+
+    #[test]
+    fn parse_stream_metadata() {
+        let names = ["SubSu", "BePxT", "AfPxT"];
+        let info = names
+            .iter()
+            .map(|name| {
+                Value::Dictionary(
+                    [("name".to_string(), Value::String(name.to_string()))]
+                        .into_iter()
+                        .collect(),
+                )
+            })
+            .collect();
+
+        let event =
+            MirroringEvent::from_plist(&bplist([("timestampInfo", Value::Array(info))])).unwrap();
+        assert_eq!(
+            event,
+            MirroringEvent::StreamMetadata {
+                timestamp_info: names.map(str::to_string).to_vec()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_display_config() {
+        let event = MirroringEvent::from_plist(&bplist([
+            ("width", Value::Real(1920.0)),
+            ("height", Value::Integer(1080.into())),
+        ]))
+        .unwrap();
+        assert_eq!(
+            event,
+            MirroringEvent::DisplayConfig {
+                width: 1920.0,
+                height: 1080.0,
+                refresh_rate: None,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_rotation_and_unknown() {
+        let event =
+            MirroringEvent::from_plist(&bplist([("rotation", Value::Integer(90.into()))])).unwrap();
+        assert_eq!(event, MirroringEvent::Rotation { degrees: 90 });
+
+        let event = MirroringEvent::from_plist(&bplist([("foo", Value::Boolean(true))])).unwrap();
+        assert!(matches!(event, MirroringEvent::Other(dict) if dict.contains_key("foo")));
+    }
+
+    #[test]
+    fn reject_garbage() {
+        assert!(MirroringEvent::from_plist(b"definitely not a plist").is_err());
+    }
+}
@@ -0,0 +1,164 @@
+//! Password protection of the receiver, HTTP Digest authentication with MD5 and without `qop`.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::{
+    HeaderValue, StatusCode,
+    header::{AUTHORIZATION, WWW_AUTHENTICATE},
+};
+use md5::{Digest as _, Md5};
+use rand::Rng;
+use subtle::ConstantTimeEq;
+
+use crate::crypto::Secret;
+
+const REALM: &str = "raop";
+
+#[derive(Debug)]
+pub struct Digest {
+    password: Secret<String>,
+    nonce: String,
+}
+
+impl Digest {
+    /// Nonce lives as long as the connection.
+    pub fn new(password: Secret<String>, mut rng: impl Rng) -> Self {
+        let mut nonce = [0u8; 16];
+        rng.fill_bytes(&mut nonce);
+
+        Self {
+            password,
+            nonce: hex::encode(nonce),
+        }
+    }
+
+    fn challenge(&self) -> HeaderValue {
+        let value = format!("Digest realm=\"{REALM}\", nonce=\"{}\"", self.nonce);
+        HeaderValue::try_from(value).expect("nonce is hex string")
+    }
+
+    /// `path` is of the request line, the codec passes on nothing else of RTSP URI.
+    fn verify(&self, method: &str, path: &str, authorization: &str) -> bool {
+        let Some(params) = authorization.strip_prefix("Digest ") else {
+            return false;
+        };
+
+        let (mut username, mut realm, mut nonce, mut uri, mut response) =
+            (None, None, None, None, None);
+        for param in params.split(',') {
+            let Some((key, value)) = param.trim().split_once('=') else {
+                continue;
+            };
+            let value = value.trim_matches('"');
+            match key {
+                "username" => username = Some(value),
+                "realm" => realm = Some(value),
+                "nonce" => nonce = Some(value),
+                "uri" => uri = Some(value),
+                "response" => response = Some(value),
+                _ => {}
+            }
+        }
+        let (Some(username), Some(realm), Some(nonce), Some(uri), Some(response)) =
+            (username, realm, nonce, uri, response)
+        else {
+            return false;
+        };
+        if realm != REALM || nonce != self.nonce || uri_path(uri) != path {
+            return false;
+        }
+
+        let ha1 = md5_hex(&[username, realm, self.password.as_str()]);
+        let ha2 = md5_hex(&[method, uri]);
+        let expected = md5_hex(&[&ha1, nonce, &ha2]);
+
+        // Senders use uppercase hex
+        let response = response.to_ascii_lowercase();
+        expected.as_bytes().ct_eq(response.as_bytes()).into()
+    }
+}
+
+pub async fn middleware(State(digest): State<Arc<Digest>>, req: Request, next: Next) -> Response {
+    // Receiver's info is public, senders ask it before the password is entered
+    if req.uri().path() == "/info" {
+        return next.run(req).await;
+    }
+
+    let authorized = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| digest.verify(req.method().as_str(), req.uri().path(), value));
+    if authorized {
+        next.run(req).await
+    } else {
+        tracing::debug!(method = %req.method(), uri = %req.uri(), "unauthorized request");
+        (
+            StatusCode::UNAUTHORIZED,
+            [(WWW_AUTHENTICATE, digest.challenge())],
+        )
+            .into_response()
+    }
+}
+
+/// Same path as the codec takes from `rtsp://host/path`, host may be bare IPv6 there.
+fn uri_path(uri: &str) -> &str {
+    let path = match uri.strip_prefix("rtsp://") {
+        Some(rest) => rest.find('/').map_or("/", |pos| &rest[pos..]),
+        None => uri,
+    };
+    path.split('?').next().unwrap_or_default()
+}
+
+fn md5_hex(parts: &[&str]) -> String {
+    let mut hasher = Md5::new();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            hasher.update(b":");
+        }
+        hasher.update(part.as_bytes());
+    }
+
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Digest;
+
+    #[test]
+    fn verify_response() {
+        let digest = Digest {
+            password: "secret".to_string().into(),
+            nonce: "3f2b8a1c9d0e4f5a6b7c8d9e0f1a2b3c".to_string(),
+        };
+        let authorization = "Digest username=\"AirPlay\", realm=\"raop\", \
+            nonce=\"3f2b8a1c9d0e4f5a6b7c8d9e0f1a2b3c\", \
+            uri=\"rtsp://192.168.1.2/3413821438\", \
+            response=\"7B47DCC4409B8E9701EA439904B5D68E\"";
+
+        assert!(digest.verify("ANNOUNCE", "/3413821438", authorization));
+        assert!(!digest.verify("SETUP", "/3413821438", authorization));
+        // Response can't be replayed for another resource
+        assert!(!digest.verify("ANNOUNCE", "/42", authorization));
+        assert!(digest.verify(
+            "ANNOUNCE",
+            "/3413821438",
+            &authorization.replace(
+                "7B47DCC4409B8E9701EA439904B5D68E",
+                "7b47dcc4409b8e9701ea439904b5d68e"
+            )
+        ));
+
+        let wrong_password = Digest {
+            password: "guess".to_string().into(),
+            ..digest
+        };
+        assert!(!wrong_password.verify("ANNOUNCE", "/3413821438", authorization));
+    }
+}
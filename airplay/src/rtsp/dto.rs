@@ -22,6 +22,8 @@ pub struct InfoResponse {
     #[serde(rename = "macAddress")]
     pub mac_addr: MacAddr6,
     pub features: u64,
+    #[serde(rename = "statusFlags")]
+    pub status_flags: u32,
     pub manufacturer: String,
     pub model: String,
     pub name: String,
@@ -74,11 +76,19 @@ pub struct SenderInfo {
     pub ekey: Option<Bytes>,
     #[serde(rename = "eiv")]
     pub eiv: Option<Bytes>,
+    /// Encryption type of `ekey`, as in `et` of RAOP's TXT record
+    #[serde(rename = "et")]
+    pub encryption_type: Option<u32>,
 
     #[serde(flatten)]
     pub timing: TimingRequest,
 }
 
+impl SenderInfo {
+    /// FairPlay SAP v2.5, its key is used with AES-GCM if `FPSAPv2p5_AES_GCM` is advertised
+    pub const ENCRYPTION_TYPE_FP_SAP_V2_5: u32 = 5;
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(tag = "timingProtocol")]
 pub enum TimingRequest {
@@ -123,10 +133,50 @@ pub struct AudioRequest {
 
 #[derive(Debug, Deserialize)]
 pub struct VideoRequest {
+    #[serde(rename = "shk")]
+    pub shared_key: Option<Bytes>,
     #[serde(rename = "streamConnectionID")]
     pub stream_connection_id: i64,
     #[serde(rename = "latencyMs")]
     pub latency_ms: u32,
+    #[serde(rename = "isScreenMirroringSession", default)]
+    pub is_screen_mirroring_session: bool,
+    #[serde(rename = "displayUUID")]
+    pub display_uuid: Option<String>,
+    #[serde(rename = "timestampInfo", default)]
+    pub timestamp_info: Vec<TimestampInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimestampInfo {
+    pub name: String,
+}
+
+/// Display description for AirPlay 1 mirroring (`GET /stream.xml`)
+#[derive(Debug, Serialize)]
+pub struct StreamDescription {
+    pub width: u32,
+    pub height: u32,
+    pub overscanned: bool,
+    #[serde(rename = "refreshRate")]
+    pub refresh_rate: f64,
+    pub version: &'static str,
+}
+
+/// AirPlay 1 mirroring request (`POST /stream`)
+#[derive(Debug, Deserialize)]
+pub struct LegacyStreamRequest {
+    /// Aes key wrapped with FairPlay
+    #[serde(rename = "param1")]
+    pub ekey: Bytes,
+    #[serde(rename = "param2")]
+    pub eiv: Bytes,
+    #[serde(rename = "sessionID")]
+    pub session_id: Option<i64>,
+    #[serde(rename = "latencyMs")]
+    pub latency_ms: Option<u32>,
+    #[serde(rename = "timestampInfo", default)]
+    pub timestamp_info: Vec<TimestampInfo>,
 }
 
 #[derive(Debug, Serialize)]
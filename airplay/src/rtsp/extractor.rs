@@ -8,7 +8,7 @@ use http::{header::CONTENT_TYPE, status::StatusCode};
 use serde::{Serialize, de::DeserializeOwned};
 use thiserror::Error;
 
-const APPLE_BPLIST_MIME: &str = "application/x-apple-binary-plist";
+use super::APPLE_BPLIST_MIME;
 
 #[derive(Debug, Error)]
 pub enum PlistRejection {
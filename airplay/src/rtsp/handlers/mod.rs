@@ -1,8 +1,13 @@
-use std::sync::{Arc, Weak, atomic::Ordering};
+use std::{
+    io,
+    sync::{Arc, Weak, atomic::Ordering},
+    time::Duration,
+};
 
 use axum::{
-    extract::{ConnectInfo, State},
-    response::IntoResponse,
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
 };
 use bytes::Bytes;
 use http::{header::CONTENT_TYPE, status::StatusCode};
@@ -14,11 +19,12 @@ use super::{
         TimingResponse, VideoRequest,
     },
     extractor::BinaryPlist,
-    state::ServiceState,
-    transport::Connection,
+    state::{ServiceState, next_stream_id},
+    transport::{Connection, SenderIdentity},
 };
 use crate::{
-    crypto::{AesIv128, ChaCha20Poly1305Key, sha512_two_step},
+    config::{Features, KeyLog, KeyLogEntry, Peer},
+    crypto::{AesIv128, AesKey128, ChaCha20Poly1305Key, sha512_two_step},
     playback::{
         ChannelHandle,
         audio::{AUDIO_FORMATS, AudioDevice, AudioParams},
@@ -30,11 +36,54 @@ use crate::{
     },
 };
 
-mod fairplay;
-
 #[tracing::instrument(level = "TRACE")]
 pub async fn generic(bytes: Bytes) {}
 
+/// Rejects senders by address and pairing identifier, the rest is checked at `SETUP`.
+pub async fn check_access<A, V, K>(
+    State(state): State<Arc<ServiceState<A, V, K>>>,
+    ConnectInfo(conn): ConnectInfo<Connection>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let pairing_id = conn.session_key.read().and_then(|key| key.pairing_id);
+    let peer = Peer {
+        pairing_id: pairing_id.as_deref(),
+        ..Peer::new(conn.remote_addr.ip())
+    };
+    match state.config.access.check_pairing(&peer) {
+        Ok(()) => next.run(req).await,
+        Err(err) => {
+            tracing::warn!(%err, remote_addr = %conn.remote_addr, "sender rejected");
+            StatusCode::from(err).into_response()
+        }
+    }
+}
+
+/// Writes the session key into key log once pairing changes it.
+pub async fn log_session_key(
+    State(keylog): State<Arc<dyn KeyLog>>,
+    ConnectInfo(conn): ConnectInfo<Connection>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let before = conn.session_key.read().map(|key| key.key_material);
+    let response = next.run(req).await;
+    if let Some(key) = conn.session_key.read()
+        && before.as_ref() != Some(&key.key_material)
+    {
+        keylog.log(
+            conn.local_addr,
+            conn.remote_addr,
+            KeyLogEntry::SessionKey {
+                key_material: &key.key_material,
+            },
+        );
+    }
+
+    response
+}
+
 #[tracing::instrument(level = "DEBUG", ret, skip(state))]
 pub async fn info<A, V, K>(
     State(state): State<Arc<ServiceState<A, V, K>>>,
@@ -45,7 +94,8 @@ pub async fn info<A, V, K>(
     let response = InfoResponse {
         device_id: state.config.mac_addr,
         mac_addr: state.config.mac_addr,
-        features: state.config.features.bits(),
+        features: state.config.advertised_features().bits(),
+        status_flags: state.config.status_flags().bits(),
         protocol_version: PROTOVERS.to_string(),
         source_version: SRCVERS.to_string(),
 
@@ -71,7 +121,13 @@ pub async fn fp_setup<A, V, K>(
     State(state): State<Arc<ServiceState<A, V, K>>>,
     body: Bytes,
 ) -> Result<Vec<u8>, StatusCode> {
-    fairplay::decode_buf(&body)
+    let Some(fairplay) = &state.config.fairplay else {
+        tracing::error!("fairplay isn't available, enable `fairplay` feature or set a provider");
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    };
+
+    fairplay
+        .decode_setup(&body)
         .inspect(|_| {
             let Ok(msg) = <[u8; _]>::try_from(&body[..]) else {
                 return;
@@ -143,6 +199,29 @@ pub async fn setup<A: AudioDevice, V: VideoDevice, K>(
     ConnectInfo(conn): ConnectInfo<Connection>,
     BinaryPlist(req): BinaryPlist<SetupRequest>,
 ) -> Result<BinaryPlist<SetupResponse>, StatusCode> {
+    let pairing_id = conn.session_key.read().and_then(|key| key.pairing_id);
+    // Streams are checked against the sender introduced earlier on this connection
+    let sender = match &req {
+        SetupRequest::SenderInfo(info) => Some(SenderIdentity {
+            device_id: info.device_id.clone(),
+            mac_addr: info.mac_addr.parse().ok(),
+        }),
+        SetupRequest::Streams { .. } => conn.sender.lock().unwrap().clone(),
+    };
+    let peer = Peer {
+        device_id: sender.as_ref().map(|sender| sender.device_id.as_str()),
+        mac_addr: sender.as_ref().and_then(|sender| sender.mac_addr),
+        pairing_id: pairing_id.as_deref(),
+        ..Peer::new(conn.remote_addr.ip())
+    };
+    state.config.access.check(&peer).map_err(|err| {
+        tracing::warn!(%err, ?peer, "setup rejected");
+        StatusCode::from(err)
+    })?;
+    if let SetupRequest::SenderInfo(_) = &req {
+        *conn.sender.lock().unwrap() = sender;
+    }
+
     match req {
         SetupRequest::SenderInfo(info) => setup_info(&state, &conn, *info).await,
         SetupRequest::Streams { requests } => setup_streams(&state, &conn, requests).await,
@@ -154,16 +233,24 @@ async fn setup_info<A, V, K>(
     state: &ServiceState<A, V, K>,
     conn: &Connection,
     SenderInfo {
-        ekey, eiv, timing, ..
+        ekey,
+        eiv,
+        encryption_type,
+        timing,
+        ..
     }: SenderInfo,
 ) -> Result<BinaryPlist<SetupResponse>, StatusCode> {
     let mut lock = state.event_channel.lock().await;
     let event_channel = match &mut *lock {
         Some(chan) => chan,
-        event_channel @ None => EventChannel::create(conn.bind_addr())
-            .await
-            .map(|chan| event_channel.insert(chan))
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        event_channel @ None => EventChannel::create(
+            conn.bind_addr(),
+            // Event channel is upgraded just like this one
+            conn.session_key.read().filter(|key| key.upgrade_channel),
+        )
+        .await
+        .map(|chan| event_channel.insert(chan))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
     };
 
     if let Some(ekey) = ekey
@@ -185,18 +272,35 @@ async fn setup_info<A, V, K>(
             return Err(StatusCode::BAD_REQUEST);
         };
 
-        let aes_key = fairplay::decrypt_key(fp_last_msg, ekey);
+        let aes_key = decrypt_key(state, &fp_last_msg, &ekey)?;
         tracing::trace!(?aes_key, ?fp_last_msg, "aes key decrypted with fairplay");
 
-        let aes_key = sha512_two_step(&aes_key, &session_key.key_material);
+        let aes_key = AesKey128::from(sha512_two_step(
+            aes_key.as_slice(),
+            &session_key.key_material,
+        ));
         tracing::trace!(
             ?aes_key,
             ?session_key,
             "additional hashing with pairing's shared secret"
         );
 
-        state.ekey.lock_write().replace(aes_key);
+        let aes_gcm = state.config.features.contains(Features::FPSAPv2p5_AES_GCM)
+            && encryption_type == Some(SenderInfo::ENCRYPTION_TYPE_FP_SAP_V2_5);
+        tracing::debug!(?encryption_type, %aes_gcm, "encryption type negotiated");
+
+        log_key(
+            state,
+            conn,
+            KeyLogEntry::AesKey {
+                key: aes_key.as_slice(),
+                iv: &eiv,
+                gcm: aes_gcm,
+            },
+        );
+        state.ekey.lock().unwrap().replace(aes_key);
         state.eiv.lock_write().replace(eiv);
+        state.aes_gcm.store(aes_gcm, Ordering::Release);
     }
 
     let timing = match timing {
@@ -217,6 +321,38 @@ async fn setup_info<A, V, K>(
     }))
 }
 
+fn decrypt_key<A, V, K>(
+    state: &ServiceState<A, V, K>,
+    fp_last_msg: &[u8],
+    ekey: &[u8],
+) -> Result<AesKey128, StatusCode> {
+    let Some(fairplay) = &state.config.fairplay else {
+        tracing::error!("fairplay isn't available, key can't be decrypted");
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    };
+
+    fairplay
+        .decrypt_key(fp_last_msg, ekey)
+        .inspect_err(|err| tracing::error!(%err, "failed to decrypt key with fairplay"))
+        .map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+fn log_key<A, V, K>(state: &ServiceState<A, V, K>, conn: &Connection, entry: KeyLogEntry<'_>) {
+    if let Some(keylog) = &state.config.keylog {
+        keylog.log(conn.local_addr, conn.remote_addr, entry);
+    }
+}
+
+/// Channel refuses keys it can't use, that's sender's fault rather than ours.
+fn channel_error(err: io::Error) -> StatusCode {
+    tracing::error!(%err, "channel couldn't be created");
+    if err.kind() == io::ErrorKind::InvalidInput {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
 #[tracing::instrument(level = "DEBUG", skip_all)]
 async fn setup_streams<A: AudioDevice, V: VideoDevice, K>(
     state: &ServiceState<A, V, K>,
@@ -225,7 +361,7 @@ async fn setup_streams<A: AudioDevice, V: VideoDevice, K>(
 ) -> Result<BinaryPlist<SetupResponse>, StatusCode> {
     let mut responses = Vec::with_capacity(requests.len());
     for stream in requests {
-        let id = state.last_stream_id.fetch_add(1, Ordering::AcqRel);
+        let id = next_stream_id();
         match match stream {
             StreamRequest::AudioBuffered(request) => {
                 setup_buffered_audio(state, conn, request, id).await
@@ -287,7 +423,16 @@ async fn setup_buffered_audio<A: AudioDevice, V, K>(
     };
     tracing::debug!(?codec, "codec parsed");
 
-    let shared_data = Arc::new(SharedData::default());
+    log_key(
+        state,
+        conn,
+        KeyLogEntry::Stream {
+            stream_type: StreamType::AudioBuffered as u32,
+            stream_connection_id,
+            shared_key: chacha_key.as_ref().map(|key| key.as_slice()),
+        },
+    );
+    let shared_data = stream_shared_data(state).await;
     let params = AudioParams {
         samples_per_frame,
         codec,
@@ -315,9 +460,11 @@ async fn setup_buffered_audio<A: AudioDevice, V, K>(
         EncryptionMaterial {
             chacha_key,
             stream_connection_id,
-            aeskey: state.ekey.read(),
+            aeskey: state.ekey.lock().unwrap().clone(),
             aesiv: state.eiv.read(),
+            aes_gcm: state.aes_gcm.load(Ordering::Acquire),
             session_key: conn.session_key.read(),
+            allow_unencrypted: state.config.features.contains(Features::AudioUnencrypted),
         },
     )
     .await
@@ -333,7 +480,7 @@ async fn setup_buffered_audio<A: AudioDevice, V, K>(
         local_data_port: chan.local_addr.port(),
         audio_buffer_size: chan.audio_buf_size,
     })
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+    .map_err(channel_error)
 }
 
 #[tracing::instrument(level = "DEBUG", ret, err, skip(state))]
@@ -380,7 +527,16 @@ async fn setup_realtime_audio<A: AudioDevice, V, K>(
     };
     tracing::debug!(?codec, "codec parsed");
 
-    let shared_data = Arc::new(SharedData::default());
+    log_key(
+        state,
+        conn,
+        KeyLogEntry::Stream {
+            stream_type: StreamType::AudioRealtime as u32,
+            stream_connection_id,
+            shared_key: chacha_key.as_ref().map(|key| key.as_slice()),
+        },
+    );
+    let shared_data = stream_shared_data(state).await;
     let params = AudioParams {
         samples_per_frame,
         codec,
@@ -408,10 +564,16 @@ async fn setup_realtime_audio<A: AudioDevice, V, K>(
         EncryptionMaterial {
             chacha_key,
             stream_connection_id,
-            aeskey: state.ekey.read(),
+            aeskey: state.ekey.lock().unwrap().clone(),
             aesiv: state.eiv.read(),
+            aes_gcm: state.aes_gcm.load(Ordering::Acquire),
             session_key: conn.session_key.read(),
+            allow_unencrypted: state.config.features.contains(Features::AudioUnencrypted),
         },
+        state
+            .config
+            .features
+            .contains(Features::ControlChannelEncrypt),
     )
     .await
     .inspect(|_| {
@@ -426,7 +588,7 @@ async fn setup_realtime_audio<A: AudioDevice, V, K>(
         local_data_port: chan.local_data_addr.port(),
         local_control_port: chan.local_control_addr.port(),
     })
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+    .map_err(channel_error)
 }
 
 #[tracing::instrument(level = "DEBUG", ret, err, skip(state))]
@@ -434,8 +596,12 @@ async fn setup_video<A, V: VideoDevice, K>(
     state: &ServiceState<A, V, K>,
     conn: &Connection,
     VideoRequest {
+        shared_key,
         stream_connection_id,
-        ..
+        latency_ms,
+        is_screen_mirroring_session,
+        display_uuid,
+        timestamp_info,
     }: VideoRequest,
     id: u64,
 ) -> Result<StreamResponse, StatusCode> {
@@ -443,15 +609,42 @@ async fn setup_video<A, V: VideoDevice, K>(
     #[allow(clippy::cast_sign_loss)]
     let stream_connection_id = stream_connection_id as u64;
 
-    let shared_data = Arc::new(SharedData::default());
-    let params = VideoParams {};
+    let chacha_key = shared_key
+        .map(|shk| {
+            ChaCha20Poly1305Key::try_from(shk.as_ref())
+                .inspect_err(|_| {
+                    tracing::error!(
+                        len = shk.len(),
+                        "insufficient length of key for video's decryption"
+                    );
+                })
+                .map_err(|_| StatusCode::BAD_REQUEST)
+        })
+        .transpose()?;
+
+    log_key(
+        state,
+        conn,
+        KeyLogEntry::Stream {
+            stream_type: StreamType::Video as u32,
+            stream_connection_id: Some(stream_connection_id),
+            shared_key: chacha_key.as_ref().map(|key| key.as_slice()),
+        },
+    );
+    let shared_data = stream_shared_data(state).await;
+    let params = VideoParams {
+        latency: Duration::from_millis(latency_ms.into()),
+        screen_mirroring: is_screen_mirroring_session,
+        display_uuid,
+        timestamp_info: timestamp_info.into_iter().map(|info| info.name).collect(),
+    };
     let stream = state
         .config
         .video
         .device
         .create(
             id,
-            VideoParams {},
+            params.clone(),
             Arc::downgrade(&shared_data) as Weak<dyn ChannelHandle>,
         )
         .await
@@ -466,11 +659,13 @@ async fn setup_video<A, V: VideoDevice, K>(
         stream,
         state.config.video.buf_size,
         EncryptionMaterial {
-            chacha_key: None,
-            aeskey: state.ekey.read(),
+            chacha_key,
+            aeskey: state.ekey.lock().unwrap().clone(),
             aesiv: state.eiv.read(),
+            aes_gcm: state.aes_gcm.load(Ordering::Acquire),
             session_key: conn.session_key.read(),
             stream_connection_id: Some(stream_connection_id),
+            allow_unencrypted: state.config.features.contains(Features::AudioUnencrypted),
         },
     )
     .await
@@ -485,5 +680,20 @@ async fn setup_video<A, V: VideoDevice, K>(
         id,
         local_data_port: chan.local_addr.port(),
     })
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+    .map_err(channel_error)
+}
+
+/// Events of the stream go to the session's event channel if it's set up.
+async fn stream_shared_data<A, V, K>(state: &ServiceState<A, V, K>) -> Arc<SharedData> {
+    let events = state
+        .event_channel
+        .lock()
+        .await
+        .as_ref()
+        .map(EventChannel::events);
+
+    Arc::new(SharedData {
+        events,
+        ..SharedData::default()
+    })
 }
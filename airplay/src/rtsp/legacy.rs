@@ -0,0 +1,291 @@
+//! AirPlay 1 screen mirroring on its well-known port. The sender asks for `GET /stream.xml`,
+//! does `POST /fp-setup` and `POST /stream`, then sends frames on the very same connection.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use bytes::{BufMut, BytesMut};
+use http::StatusCode;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use super::{
+    dto::{LegacyStreamRequest, StreamDescription},
+    state::{FairplayMsg, next_stream_id},
+};
+use crate::{
+    config::{Config, KeyLogEntry, Keychain, Peer},
+    crypto::AesIv128,
+    playback::{
+        ChannelHandle,
+        audio::AudioDevice,
+        video::{VideoDevice, VideoParams},
+    },
+    streaming::{self, EncryptionMaterial, Message, SharedData, VideoChannel},
+};
+
+/// Senders don't ask for the port, it's always this one
+pub const LEGACY_MIRRORING_PORT: u16 = 7100;
+
+const APPLE_XML_PLIST_MIME: &str = "text/x-apple-plist+xml";
+
+/// Pause after a failed `accept`, e.g. when out of file descriptors, so it doesn't spin.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Serves AirPlay 1 mirroring, the listener must live as long as the receiver.
+pub async fn serve_legacy_mirroring<A, V, K>(listener: TcpListener, config: Arc<Config<A, V, K>>)
+where
+    A: AudioDevice,
+    V: VideoDevice,
+    K: Keychain,
+{
+    loop {
+        let (tcp_stream, remote_addr) = match listener.accept().await {
+            Ok(res) => res,
+            Err(err) => {
+                tracing::error!(%err, "couldn't accept legacy mirroring connection");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+
+        let config = Arc::clone(&config);
+        let id = next_stream_id();
+        tokio::spawn(async move {
+            if let Err(err) = connection(id, tcp_stream, remote_addr, &config).await {
+                tracing::warn!(%err, %remote_addr, "legacy mirroring connection closed");
+            }
+        });
+    }
+}
+
+#[tracing::instrument(level = "DEBUG", skip(tcp_stream, config))]
+async fn connection<A, V: VideoDevice, K>(
+    id: u64,
+    mut tcp_stream: TcpStream,
+    remote_addr: SocketAddr,
+    config: &Config<A, V, K>,
+) -> io::Result<()> {
+    let local_addr = tcp_stream.local_addr()?;
+    let mut buf = BytesMut::new();
+    let mut fp_last_msg = None;
+
+    // Requests are still read, so the sender gets a status instead of a reset
+    let reject = if let Err(err) = config.access.check(&Peer::new(remote_addr.ip())) {
+        tracing::warn!(%err, "sender rejected");
+        Some(StatusCode::from(err))
+    } else if config.password.is_some() {
+        tracing::warn!("password protection isn't supported by legacy mirroring");
+        Some(StatusCode::FORBIDDEN)
+    } else {
+        None
+    };
+
+    loop {
+        let Some(request) = streaming::parse_message(&mut buf)? else {
+            if tcp_stream.read_buf(&mut buf).await? == 0 {
+                return Ok(());
+            }
+            continue;
+        };
+        tracing::debug!(start_line = %request.start_line, "legacy mirroring request");
+
+        let mut path = request.start_line.split_whitespace();
+        let result = match (reject, path.next(), path.next()) {
+            (Some(status), ..) => Err(status),
+            (None, Some("GET"), Some("/stream.xml")) => stream_xml(config),
+            (None, Some("POST"), Some("/fp-setup")) => fp_setup(config, &request, &mut fp_last_msg),
+            (None, Some("POST"), Some("/stream")) => {
+                let conn = (local_addr, remote_addr);
+                let stream = stream(id, config, conn, &request, fp_last_msg.as_ref()).await;
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(status) => {
+                        write_response(&mut tcp_stream, &request, Err(status)).await?;
+                        continue;
+                    }
+                };
+                write_response(&mut tcp_stream, &request, Ok(None)).await?;
+
+                // Frames right behind the request may be read already
+                let reader = (&buf[..]).chain(tcp_stream);
+                return VideoChannel::legacy(
+                    reader,
+                    &stream.shared_data,
+                    stream.device_stream,
+                    config.video.buf_size,
+                    stream.keys,
+                )
+                .await;
+            }
+            _ => {
+                tracing::warn!(start_line = %request.start_line, "unknown legacy request");
+                Err(StatusCode::NOT_FOUND)
+            }
+        };
+
+        write_response(&mut tcp_stream, &request, result).await?;
+    }
+}
+
+type Body = Option<(&'static str, Vec<u8>)>;
+
+fn stream_xml<A, V, K>(config: &Config<A, V, K>) -> Result<Body, StatusCode> {
+    let description = StreamDescription {
+        width: config.video.width,
+        height: config.video.height,
+        overscanned: false,
+        refresh_rate: 1.0 / f64::from(config.video.fps.max(1)),
+        version: "130.14",
+    };
+
+    let mut body = Vec::new();
+    plist::to_writer_xml(&mut body, &description)
+        .inspect_err(|err| tracing::error!(%err, "stream description isn't serialized"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Some((APPLE_XML_PLIST_MIME, body)))
+}
+
+fn fp_setup<A, V, K>(
+    config: &Config<A, V, K>,
+    request: &Message,
+    fp_last_msg: &mut Option<FairplayMsg>,
+) -> Result<Body, StatusCode> {
+    let Some(fairplay) = &config.fairplay else {
+        tracing::error!("fairplay isn't available, enable `fairplay` feature or set a provider");
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    };
+
+    let body = fairplay
+        .decode_setup(&request.body)
+        .inspect_err(|err| tracing::error!(%err, "failed to decode fairplay"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Ok(msg) = <[u8; _]>::try_from(&request.body[..]) {
+        fp_last_msg.replace(msg);
+        tracing::trace!("fairplay3 last message is saved");
+    }
+
+    Ok(Some(("application/octet-stream", body)))
+}
+
+struct LegacyStream<S> {
+    shared_data: Arc<SharedData>,
+    device_stream: S,
+    keys: EncryptionMaterial,
+}
+
+async fn stream<A, V: VideoDevice, K>(
+    id: u64,
+    config: &Config<A, V, K>,
+    (local_addr, remote_addr): (SocketAddr, SocketAddr),
+    request: &Message,
+    fp_last_msg: Option<&FairplayMsg>,
+) -> Result<LegacyStream<V::Stream>, StatusCode> {
+    let LegacyStreamRequest {
+        ekey,
+        eiv,
+        session_id,
+        latency_ms,
+        timestamp_info,
+    } = plist::from_bytes(&request.body).map_err(|err| {
+        tracing::error!(%err, "invalid stream request");
+        StatusCode::BAD_REQUEST
+    })?;
+
+    let Ok(eiv) = AesIv128::try_from(eiv.as_ref()) else {
+        tracing::error!(len=%eiv.len(), "invalid length of passed iv");
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    let Some(fp_last_msg) = fp_last_msg else {
+        tracing::error!("fairplay3 handshake must be present");
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    let Some(fairplay) = &config.fairplay else {
+        tracing::error!("fairplay isn't available, key can't be decrypted");
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    };
+
+    let aes_key = fairplay
+        .decrypt_key(fp_last_msg, &ekey)
+        .inspect_err(|err| tracing::error!(%err, "failed to decrypt key with fairplay"))
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    tracing::trace!(?aes_key, "aes key decrypted with fairplay");
+    if let Some(keylog) = &config.keylog {
+        keylog.log(
+            local_addr,
+            remote_addr,
+            KeyLogEntry::AesKey {
+                key: aes_key.as_slice(),
+                iv: &eiv,
+                gcm: false,
+            },
+        );
+    }
+    tracing::debug!(%id, ?session_id, "legacy mirroring stream");
+
+    let shared_data = Arc::new(SharedData::default());
+    let params = VideoParams {
+        latency: Duration::from_millis(latency_ms.unwrap_or_default().into()),
+        screen_mirroring: true,
+        display_uuid: None,
+        timestamp_info: timestamp_info.into_iter().map(|info| info.name).collect(),
+    };
+    let device_stream = config
+        .video
+        .device
+        .create(
+            id,
+            params.clone(),
+            Arc::downgrade(&shared_data) as Weak<dyn ChannelHandle>,
+        )
+        .await
+        .inspect(|_| tracing::trace!("new stream opened"))
+        .inspect_err(|err| tracing::error!(%err, ?params, "stream couldn't be created"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(LegacyStream {
+        shared_data,
+        device_stream,
+        keys: EncryptionMaterial {
+            chacha_key: None,
+            aeskey: Some(aes_key),
+            aesiv: Some(eiv),
+            aes_gcm: false,
+            session_key: None,
+            stream_connection_id: None,
+            allow_unencrypted: false,
+        },
+    })
+}
+
+async fn write_response(
+    tcp_stream: &mut TcpStream,
+    request: &Message,
+    result: Result<Body, StatusCode>,
+) -> io::Result<()> {
+    let (status, body) = match result {
+        Ok(body) => (StatusCode::OK, body),
+        Err(status) => (status, None),
+    };
+    let (content_type, body) = body.unwrap_or_default();
+
+    let mut response = BytesMut::new();
+    response.put_slice(format!("HTTP/1.1 {status}\r\n").as_bytes());
+    if let Some(cseq) = request.header("CSeq") {
+        response.put_slice(format!("CSeq: {cseq}\r\n").as_bytes());
+    }
+    if !body.is_empty() {
+        response.put_slice(format!("Content-Type: {content_type}\r\n").as_bytes());
+    }
+    response.put_slice(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes());
+    response.put_slice(&body);
+
+    tcp_stream.write_all(&response).await
+}
@@ -4,28 +4,39 @@ use axum::{
     Extension, Router,
     extract::{ConnectInfo, Request},
     handler::Handler,
-    http::HeaderName,
+    http::{HeaderName, StatusCode},
+    middleware,
     response::Response,
     routing::{any, get, post},
     serve::IncomingStream,
 };
+pub use legacy::{LEGACY_MIRRORING_PORT, serve_legacy_mirroring};
 use tower::{Service, service_fn};
 use tower_http::propagate_header::PropagateHeaderLayer;
 pub use transport::TcpListenerWithRtspRemap as Listener;
 use yoke::Yoke;
 
 use crate::{
-    config::{Config, Keychain, Pairing},
+    config::{Config, Keychain, Pairing, PinCode},
     pairing,
     playback::{audio::AudioDevice, video::VideoDevice},
 };
 
+mod auth;
 mod dto;
 mod extractor;
 mod handlers;
+mod legacy;
 mod state;
 mod transport;
 
+pub(crate) const APPLE_BPLIST_MIME: &str = "application/x-apple-binary-plist";
+
+/// RTSP's Connection Authorization Required, asks sender to pair (with PIN) first
+pub(crate) fn connection_authorization_required() -> StatusCode {
+    StatusCode::from_u16(470).expect("valid status code")
+}
+
 pub fn service_factory<A, V, K>(
     config: Arc<Config<A, V, K>>,
 ) -> impl for<'a> Service<
@@ -46,8 +57,16 @@ where
     V: VideoDevice,
     K: Keychain,
 {
+    if let (Pairing::Legacy, Some(PinCode::HomeKit(_))) = (&config.pairing, config.pin) {
+        tracing::warn!("legacy senders can enter only 4 digits, use `PinCode::Legacy`");
+    }
+
+    // Must outlive connections, otherwise reconnect resets attempts
+    let throttle = Arc::new(pairing::homekit::Throttle::new(config.pairing_limits));
+
     service_fn(move |incoming: IncomingStream<'_, Listener>| {
         let config = Arc::clone(&config);
+        let throttle = Arc::clone(&throttle);
         let conn = incoming.remote_addr().clone();
         async move {
             let state = Arc::new(state::ServiceState::new(config));
@@ -69,18 +88,42 @@ where
                 .erase_arc_cart();
             match state.config.pairing {
                 Pairing::Legacy => {
-                    router =
-                        router.merge(pairing::legacy::router(keychain, conn.session_key.clone()));
+                    router = router.merge(pairing::legacy::router(
+                        keychain,
+                        conn.session_key.clone(),
+                        state.config.pin,
+                        state.config.pin_display.clone(),
+                        throttle,
+                    ));
                 }
-                Pairing::HomeKit => {
+                Pairing::HomeKit { mode } => {
+                    let settings = pairing::homekit::Settings {
+                        mode,
+                        pin: state.config.pin,
+                        pin_display: state.config.pin_display.clone(),
+                        mfi: state.config.mfi.clone(),
+                        approval: state.config.pairing_approval.clone(),
+                        access: state.config.access.clone(),
+                        throttle,
+                    };
                     router = router.merge(pairing::homekit::router(
                         keychain,
                         conn.session_key.clone(),
-                        state.config.pin,
+                        conn.remote_addr,
+                        settings,
                     ));
                 }
             }
 
+            // Layers are applied below to all routes, but the state is moved into the last one
+            let access_state = Arc::clone(&state);
+            let digest = state
+                .config
+                .password
+                .clone()
+                .map(|password| Arc::new(auth::Digest::new(password, rand::rng())));
+            let keylog = state.config.keylog.clone();
+
             // Custom RTSP methods
             router = router.route(
                 "/{media_id}",
@@ -100,6 +143,20 @@ where
                 }),
             );
 
+            if let Some(digest) = digest {
+                router = router.layer(middleware::from_fn_with_state(digest, auth::middleware));
+            }
+            if let Some(keylog) = keylog {
+                router = router.layer(middleware::from_fn_with_state(
+                    keylog,
+                    handlers::log_session_key,
+                ));
+            }
+            // Senders forbidden by address are rejected before anything else
+            router = router.layer(middleware::from_fn_with_state(
+                access_state,
+                handlers::check_access,
+            ));
             // CSeq is required for RTSP protocol
             router = router.layer(PropagateHeaderLayer::new(HeaderName::from_static("cseq")));
             router = router.layer(Extension(ConnectInfo(conn)));
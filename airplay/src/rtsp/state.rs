@@ -1,4 +1,7 @@
-use std::sync::{Arc, Mutex, Weak, atomic::AtomicU64};
+use std::sync::{
+    Arc, Mutex, Weak,
+    atomic::{AtomicBool, AtomicU64, Ordering},
+};
 
 use seqlock::SeqLock;
 use tokio::sync::Mutex as AsyncMutex;
@@ -13,11 +16,19 @@ use crate::{
 
 pub type FairplayMsg = [u8; 164];
 
+/// Ids of streams passed to devices, unique among all connections including legacy mirroring.
+pub fn next_stream_id() -> u64 {
+    static LAST_STREAM_ID: AtomicU64 = AtomicU64::new(0);
+    LAST_STREAM_ID.fetch_add(1, Ordering::Relaxed)
+}
+
 pub struct ServiceState<ADev, VDev, KC> {
-    pub last_stream_id: AtomicU64,
     pub fp_last_msg: SeqLock<Option<FairplayMsg>>,
-    pub ekey: SeqLock<Option<AesKey128>>,
+    /// Not a [`SeqLock`], the key isn't `Copy` to be zeroized on drop
+    pub ekey: Mutex<Option<AesKey128>>,
     pub eiv: SeqLock<Option<AesIv128>>,
+    /// Sender negotiated AES-GCM for the key instead of CBC/CTR
+    pub aes_gcm: AtomicBool,
     pub event_channel: AsyncMutex<Option<EventChannel>>,
     pub stream_channels: Mutex<WeakValueHashMap<(u64, u32), Weak<SharedData>>>,
 
@@ -27,10 +38,10 @@ pub struct ServiceState<ADev, VDev, KC> {
 impl<A, V, K> ServiceState<A, V, K> {
     pub fn new(config: Arc<Config<A, V, K>>) -> Self {
         Self {
-            last_stream_id: AtomicU64::default(),
             fp_last_msg: SeqLock::default(),
-            ekey: SeqLock::default(),
+            ekey: Mutex::default(),
             eiv: SeqLock::default(),
+            aes_gcm: AtomicBool::default(),
             event_channel: AsyncMutex::default(),
             stream_channels: Mutex::default(),
 
@@ -1,9 +1,11 @@
 use std::{
     io,
     net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::{Arc, Mutex},
 };
 
 use axum::serve::Listener;
+use macaddr::MacAddr6;
 use tokio::{io::Result, net::{TcpSocket, TcpStream}};
 use tokio_dual_stack::{DualStackTcpListener, Tcp as _};
 use tokio_util::{
@@ -21,6 +23,14 @@ pub struct TcpListenerWithRtspRemap {
     bind_addr6: SocketAddrV6,
 }
 
+/// Sender as introduced by the first `SETUP`, streams set up later on the connection don't
+/// repeat it.
+#[derive(Debug, Clone)]
+pub struct SenderIdentity {
+    pub device_id: String,
+    pub mac_addr: Option<MacAddr6>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Connection {
     pub bind_addr4: SocketAddrV4,
@@ -28,6 +38,7 @@ pub struct Connection {
     pub local_addr: SocketAddr,
     pub remote_addr: SocketAddr,
     pub session_key: SharedSessionKey,
+    pub sender: Arc<Mutex<Option<SenderIdentity>>>,
 }
 
 impl Connection {
@@ -105,6 +116,7 @@ impl Listener for TcpListenerWithRtspRemap {
                 ))),
                 Connection {
                     session_key,
+                    sender: Arc::default(),
                     local_addr,
                     remote_addr,
                     bind_addr4: self.bind_addr4,
@@ -5,22 +5,35 @@ use std::{
 };
 
 use derivative::Derivative;
-use tokio::net::{TcpListener, UdpSocket};
+use tokio::{
+    io::AsyncRead,
+    net::{TcpListener, UdpSocket},
+    sync::mpsc,
+};
 
 use crate::{
     crypto::{AesIv128, AesKey128, ChaCha20Poly1305Key},
     pairing::SessionKey,
-    playback::{ChannelHandle, audio::AudioStream, video::VideoStream},
+    playback::{ChannelHandle, Event, audio::AudioStream, video::VideoStream},
 };
 
 mod processing;
 mod sync;
 
+pub(crate) use processing::event::{Message, parse as parse_message};
+
+#[cfg(feature = "capture")]
+pub(crate) use processing::{
+    Encryption, build_audio_cipher, build_video_cipher, crypto, header, video_packet_kind,
+};
+
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub struct EventChannel {
     local_addr: SocketAddr,
     #[derivative(Debug = "ignore")]
+    events: mpsc::Sender<Event>,
+    #[derivative(Debug = "ignore")]
     waker_flag: Arc<sync::WakerFlag>,
 }
 
@@ -44,6 +57,8 @@ pub struct VideoChannel {
 #[derive(Default)]
 pub struct SharedData {
     pub waker_flag: sync::WakerFlag,
+    /// Sender of the session's event channel, if it's set up
+    pub events: Option<mpsc::Sender<Event>>,
 }
 
 #[derive(Debug)]
@@ -53,27 +68,38 @@ pub struct EncryptionMaterial {
     pub session_key: Option<SessionKey>,
     pub aeskey: Option<AesKey128>,
     pub aesiv: Option<AesIv128>,
+    /// AES key is used with GCM instead of CBC/CTR, if `FPSAPv2p5_AES_GCM` is negotiated
+    pub aes_gcm: bool,
+    /// Accept the stream as is if no keys are passed, set by `AudioUnencrypted` feature for audio
+    /// and video alike
+    pub allow_unencrypted: bool,
 }
 
 impl EventChannel {
-    #[tracing::instrument(ret, err)]
-    pub async fn create(bind_addr: IpAddr) -> io::Result<Self> {
+    /// Events are queued until the sender connects, a few are enough for notifications.
+    const EVENTS_CAPACITY: usize = 32;
+
+    /// Channel is encrypted with the session key if it's passed.
+    #[tracing::instrument(ret, err, skip(session_key))]
+    pub async fn create(bind_addr: IpAddr, session_key: Option<SessionKey>) -> io::Result<Self> {
         let listener = TcpListener::bind(SocketAddr::new(bind_addr, 0)).await?;
         let local_addr = listener.local_addr()?;
         tracing::info!(%local_addr, "created new listener");
 
+        let (events, events_rx) = mpsc::channel(Self::EVENTS_CAPACITY);
         let waker_flag = Arc::new(sync::WakerFlag::default());
         let wf = Arc::clone(&waker_flag);
         tokio::spawn(async move {
             tokio::select! {
                 () = &*wf => {}
-                () = processing::event_processor(listener) => {}
+                () = processing::event_processor(listener, session_key, events_rx) => {}
             };
             tracing::info!("event listener done");
         });
 
         Ok(EventChannel {
             local_addr,
+            events,
             waker_flag,
         })
     }
@@ -81,6 +107,11 @@ impl EventChannel {
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
     }
+
+    /// Sender of events to be pushed to the connected sender.
+    pub fn events(&self) -> mpsc::Sender<Event> {
+        self.events.clone()
+    }
 }
 
 impl AudioBufferedChannel {
@@ -139,7 +170,6 @@ impl AudioBufferedChannel {
 }
 
 impl AudioRealtimeChannel {
-    #[allow(clippy::too_many_arguments)]
     #[tracing::instrument(ret, err, skip(shared_data, stream))]
     pub async fn create(
         bind_addr: IpAddr,
@@ -148,7 +178,20 @@ impl AudioRealtimeChannel {
         stream: impl AudioStream,
         audio_buf_size: u32,
         keys: EncryptionMaterial,
+        encrypt_control: bool,
     ) -> io::Result<Self> {
+        // Control packets are sealed with keys of the HomeKit session only
+        let control_cipher = keys
+            .session_key
+            .as_ref()
+            .zip(keys.stream_connection_id)
+            .filter(|_| encrypt_control)
+            .map(|(key, stream_connection_id)| {
+                processing::crypto::ControlCipher::new(&key.key_material, stream_connection_id)
+            });
+        if encrypt_control && control_cipher.is_none() {
+            tracing::debug!("no session keys, control channel isn't encrypted");
+        }
         let encryption = processing::Encryption::try_from(keys)?;
 
         let data_socket = UdpSocket::bind(SocketAddr::new(bind_addr, 0)).await?;
@@ -169,7 +212,11 @@ impl AudioRealtimeChannel {
                     audio_buf_size,
                     encryption,
                 );
-                let control = processing::control_processor(expected_remote_addr, control_socket);
+                let control = processing::control_processor(
+                    expected_remote_addr,
+                    control_socket,
+                    control_cipher,
+                );
 
                 let (first, second) = tokio::join!(data, control);
                 first.or(second)
@@ -192,7 +239,6 @@ impl AudioRealtimeChannel {
 }
 
 impl VideoChannel {
-    #[allow(clippy::too_many_arguments)]
     #[tracing::instrument(ret, err, skip(shared_data, stream))]
     pub async fn create(
         bind_addr: IpAddr,
@@ -242,6 +288,29 @@ impl VideoChannel {
 
         Ok(Self { local_addr })
     }
+
+    /// AirPlay 1 mirroring, frames follow `POST /stream` on the connection of the request.
+    #[tracing::instrument(err, skip_all)]
+    pub async fn legacy(
+        reader: impl AsyncRead + Unpin,
+        shared_data: &SharedData,
+        stream: impl VideoStream,
+        video_buf_size: u32,
+        keys: EncryptionMaterial,
+    ) -> io::Result<()> {
+        let encryption = processing::Encryption::try_from(keys)?;
+        let task = processing::video_processor(reader, &stream, video_buf_size, encryption);
+
+        tokio::select! {
+            () = &shared_data.waker_flag => {},
+            res = task => match remap_io_error_if_needed(res) {
+                Ok(()) => stream.on_ok(),
+                Err(err) => stream.on_err(err.into()),
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Drop for EventChannel {
@@ -254,6 +323,18 @@ impl ChannelHandle for SharedData {
     fn close(&self) {
         self.waker_flag.set_and_wake();
     }
+
+    fn send_event(&self, event: Event) -> bool {
+        let Some(events) = &self.events else {
+            tracing::debug!(?event, "no event channel");
+            return false;
+        };
+
+        events
+            .try_send(event)
+            .inspect_err(|err| tracing::warn!(%err, "event dropped"))
+            .is_ok()
+    }
 }
 
 fn remap_io_error_if_needed(res: io::Result<()>) -> io::Result<()> {
@@ -1,7 +1,10 @@
 use aes::cipher::{BlockDecryptMut, KeyIvInit as _, StreamCipher as _, block_padding::NoPadding};
+use aes_gcm::{Aes128Gcm, Tag};
 use bytes::BytesMut;
 use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit as _, Nonce, aead::AeadInOut as _};
+use thiserror::Error;
 
+use super::replay::ReplayWindow;
 use crate::{
     crypto::{AesIv128, AesKey128, ChaCha20Poly1305Key, hkdf, sha512_two_step},
     playback::audio::AudioPacket,
@@ -10,18 +13,33 @@ use crate::{
 type AesCbc128 = cbc::Decryptor<aes::Aes128>;
 type AesCtr128BE = ctr::Ctr128BE<aes::Aes128>;
 
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum DecryptError {
+    #[error("packet is too short")]
+    Malformed,
+    #[error("packet isn't authenticated")]
+    Unauthenticated,
+    #[error("packet is replayed")]
+    Replayed,
+}
+
 pub trait AudioCipher {
-    fn decrypt(&self, packet: &mut BytesMut) -> Result<(), ()>;
+    fn decrypt(&mut self, packet: &mut BytesMut) -> Result<(), DecryptError>;
 }
 
 pub struct ChachaAudioCipher {
     inner: ChaCha20Poly1305,
+    replay_window: Option<ReplayWindow>,
 }
 
 impl ChachaAudioCipher {
+    // Tag and explicit part of nonce
+    const TRAILER_LEN: usize = 16 + 8;
+
     pub fn from_key(key: ChaCha20Poly1305Key) -> Self {
         Self {
-            inner: ChaCha20Poly1305::new(&Key::from(key)),
+            inner: ChaCha20Poly1305::new(&Key::from(*key)),
+            replay_window: None,
         }
     }
 
@@ -33,16 +51,40 @@ impl ChachaAudioCipher {
         );
         let inner = ChaCha20Poly1305::new(&Key::from(key));
 
-        Self { inner }
+        Self {
+            inner,
+            replay_window: None,
+        }
+    }
+
+    /// Drops packets whose nonce was already seen, for datagrams that may be replayed.
+    pub fn with_replay_protection(mut self) -> Self {
+        self.replay_window = Some(ReplayWindow::default());
+        self
     }
 }
 
 impl AudioCipher for ChachaAudioCipher {
-    fn decrypt(&self, packet: &mut BytesMut) -> Result<(), ()> {
+    fn decrypt(&mut self, packet: &mut BytesMut) -> Result<(), DecryptError> {
+        if packet.len() < AudioPacket::HEADER_LEN + Self::TRAILER_LEN {
+            return Err(DecryptError::Malformed);
+        }
+
+        let payload_len = packet.len() - 8;
+        let counter = {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&packet[payload_len..]);
+            u64::from_le_bytes(buf)
+        };
+        if let Some(window) = &self.replay_window
+            && !window.check(counter)
+        {
+            return Err(DecryptError::Replayed);
+        }
+
         let nonce = {
             let mut buf = [0u8; 12];
-            let payload_len = packet.len() - 8;
-            buf[4..].copy_from_slice(&packet[payload_len..]);
+            buf[4..].copy_from_slice(&counter.to_le_bytes());
             packet.truncate(payload_len);
 
             buf
@@ -51,9 +93,13 @@ impl AudioCipher for ChachaAudioCipher {
 
         self.inner
             .decrypt_in_place(&Nonce::from(nonce), &packet[4..12], &mut payload)
-            .map_err(|_| ())?;
+            .map_err(|_| DecryptError::Unauthenticated)?;
 
         packet.unsplit(payload);
+        // Only authenticated packets move the window
+        if let Some(window) = &mut self.replay_window {
+            window.update(counter);
+        }
 
         Ok(())
     }
@@ -66,13 +112,13 @@ pub struct AesAudioCipher {
 impl AesAudioCipher {
     pub fn new(key: AesKey128, eiv: AesIv128) -> Self {
         Self {
-            aescbc: AesCbc128::new(&key.into(), eiv.as_ref().into()),
+            aescbc: AesCbc128::new(&(*key).into(), eiv.as_ref().into()),
         }
     }
 }
 
 impl AudioCipher for AesAudioCipher {
-    fn decrypt(&self, packet: &mut BytesMut) -> Result<(), ()> {
+    fn decrypt(&mut self, packet: &mut BytesMut) -> Result<(), DecryptError> {
         let payload = &mut packet[AudioPacket::HEADER_LEN..];
         let encrypted_len = payload.len() - (payload.len() % 16);
         let _ = self
@@ -84,6 +130,83 @@ impl AudioCipher for AesAudioCipher {
     }
 }
 
+pub struct AesGcmAudioCipher {
+    inner: Aes128Gcm,
+    salt: [u8; 4],
+    replay_window: Option<ReplayWindow>,
+}
+
+impl AesGcmAudioCipher {
+    // Tag and explicit part of nonce
+    const TRAILER_LEN: usize = 16 + 8;
+
+    /// Cipher of FairPlay SAP v2.5, packets are sealed like ChaCha audio. Nonce is built as in
+    /// TLS (RFC 5288): salt is the start of iv, explicit part follows the tag.
+    pub fn new(key: AesKey128, iv: AesIv128) -> Self {
+        let mut salt = [0u8; 4];
+        salt.copy_from_slice(&iv[..4]);
+
+        Self {
+            inner: Aes128Gcm::new(&(*key).into()),
+            salt,
+            replay_window: None,
+        }
+    }
+
+    /// Drops packets whose nonce was already seen, for datagrams that may be replayed.
+    pub fn with_replay_protection(mut self) -> Self {
+        self.replay_window = Some(ReplayWindow::default());
+        self
+    }
+}
+
+impl AudioCipher for AesGcmAudioCipher {
+    fn decrypt(&mut self, packet: &mut BytesMut) -> Result<(), DecryptError> {
+        if packet.len() < AudioPacket::HEADER_LEN + Self::TRAILER_LEN {
+            return Err(DecryptError::Malformed);
+        }
+
+        let payload_len = packet.len() - 8;
+        let mut explicit = [0u8; 8];
+        explicit.copy_from_slice(&packet[payload_len..]);
+        let counter = u64::from_le_bytes(explicit);
+        if let Some(window) = &self.replay_window
+            && !window.check(counter)
+        {
+            return Err(DecryptError::Replayed);
+        }
+
+        let nonce = {
+            let mut buf = [0u8; 12];
+            buf[..4].copy_from_slice(&self.salt);
+            buf[4..].copy_from_slice(&explicit);
+
+            buf
+        };
+        let tag_pos = payload_len - 16;
+        let tag =
+            Tag::try_from(&packet[tag_pos..payload_len]).map_err(|_| DecryptError::Malformed)?;
+        let (header, payload) = packet.split_at_mut(AudioPacket::HEADER_LEN);
+
+        self.inner
+            .decrypt_inout_detached(
+                &nonce.into(),
+                &header[4..12],
+                (&mut payload[..tag_pos - AudioPacket::HEADER_LEN]).into(),
+                &tag,
+            )
+            .map_err(|_| DecryptError::Unauthenticated)?;
+
+        packet.truncate(tag_pos);
+        // Only authenticated packets move the window
+        if let Some(window) = &mut self.replay_window {
+            window.update(counter);
+        }
+
+        Ok(())
+    }
+}
+
 pub trait VideoCipher {
     fn decrypt(&mut self, header: [u8; 128], payload: &mut BytesMut) -> Result<(), ()>;
 }
@@ -95,14 +218,23 @@ pub struct AesVideoCipher {
 }
 
 impl AesVideoCipher {
+    /// Cipher for AirPlay 1 mirroring, where key and iv are used as is.
+    pub fn new(key: AesKey128, iv: AesIv128) -> Self {
+        Self {
+            aesctr: AesCtr128BE::new((&*key).into(), (&iv).into()),
+            og: [0; 16],
+            next_decrypt_count: 0,
+        }
+    }
+
     pub fn from_key_and_id(key: AesKey128, stream_connection_id: u64) -> Self {
         let aes = sha512_two_step(
             format!("AirPlayStreamKey{stream_connection_id}").as_bytes(),
-            &key,
+            key.as_slice(),
         );
         let iv = sha512_two_step(
             format!("AirPlayStreamIV{stream_connection_id}").as_bytes(),
-            &key,
+            key.as_slice(),
         );
         Self {
             aesctr: AesCtr128BE::new((&aes).into(), (&iv).into()),
@@ -150,6 +282,13 @@ pub struct ChachaVideoCipher {
 }
 
 impl ChachaVideoCipher {
+    pub fn from_key(key: ChaCha20Poly1305Key) -> Self {
+        Self {
+            inner: ChaCha20Poly1305::new(&Key::from(*key)),
+            count: 0,
+        }
+    }
+
     pub fn from_secret_and_id(shared_secret: &[u8], stream_connection_id: u64) -> Self {
         let key = hkdf(
             shared_secret,
@@ -181,11 +320,314 @@ impl VideoCipher for ChachaVideoCipher {
     }
 }
 
+pub struct AesGcmVideoCipher {
+    inner: Aes128Gcm,
+    salt: [u8; 4],
+    count: u64,
+}
+
+impl AesGcmVideoCipher {
+    /// Cipher of FairPlay SAP v2.5 for a key used as is. Only the first 4 bytes of iv are
+    /// taken, as salt of the nonce, frame counter makes the rest.
+    pub fn new(key: AesKey128, iv: AesIv128) -> Self {
+        let mut salt = [0u8; 4];
+        salt.copy_from_slice(&iv[..4]);
+
+        Self {
+            inner: Aes128Gcm::new(&(*key).into()),
+            salt,
+            count: 0,
+        }
+    }
+
+    pub fn from_key_and_id(key: AesKey128, stream_connection_id: u64) -> Self {
+        let aes = sha512_two_step(
+            format!("AirPlayStreamKey{stream_connection_id}").as_bytes(),
+            key.as_slice(),
+        );
+        let iv = sha512_two_step(
+            format!("AirPlayStreamIV{stream_connection_id}").as_bytes(),
+            key.as_slice(),
+        );
+
+        Self::new(aes.into(), iv)
+    }
+}
+
+impl VideoCipher for AesGcmVideoCipher {
+    fn decrypt(&mut self, header: [u8; 128], payload: &mut BytesMut) -> Result<(), ()> {
+        let nonce = {
+            let mut buf = [0u8; 12];
+            buf[..4].copy_from_slice(&self.salt);
+            buf[4..].copy_from_slice(&self.count.to_le_bytes());
+
+            buf
+        };
+        let tag_pos = payload.len().checked_sub(16).ok_or(())?;
+        let tag = Tag::try_from(&payload[tag_pos..]).map_err(|_| ())?;
+
+        self.inner
+            .decrypt_inout_detached(
+                &nonce.into(),
+                &header,
+                (&mut payload[..tag_pos]).into(),
+                &tag,
+            )
+            .map_err(|_| ())?;
+        payload.truncate(tag_pos);
+        self.count += 1;
+
+        Ok(())
+    }
+}
+
+/// Cipher of realtime audio's control packets sent by the sender. Key is derived from the
+/// session like the data stream's one, packets are sealed like ChaCha audio: header is
+/// authenticated, tag and explicit part of nonce follow the payload.
+pub struct ControlCipher {
+    inner: ChaCha20Poly1305,
+    replay_window: ReplayWindow,
+}
+
+impl ControlCipher {
+    /// RTCP-like header, kept in plain
+    pub const HEADER_LEN: usize = 4;
+    // Tag and explicit part of nonce
+    const TRAILER_LEN: usize = 16 + 8;
+
+    pub fn new(shared_secret: &[u8], stream_connection_id: u64) -> Self {
+        let key = hkdf(
+            shared_secret,
+            format!("ControlStream-Salt{stream_connection_id}").as_bytes(),
+            b"ControlStream-Output-Encryption-Key",
+        );
+
+        Self {
+            inner: ChaCha20Poly1305::new(&Key::from(key)),
+            replay_window: ReplayWindow::default(),
+        }
+    }
+
+    pub fn decrypt(&mut self, packet: &mut BytesMut) -> Result<(), DecryptError> {
+        if packet.len() < Self::HEADER_LEN + Self::TRAILER_LEN {
+            return Err(DecryptError::Malformed);
+        }
+
+        let payload_len = packet.len() - 8;
+        let mut counter = [0u8; 8];
+        counter.copy_from_slice(&packet[payload_len..]);
+        if !self.replay_window.check(u64::from_le_bytes(counter)) {
+            return Err(DecryptError::Replayed);
+        }
+
+        let nonce = {
+            let mut buf = [0u8; 12];
+            buf[4..].copy_from_slice(&counter);
+
+            buf
+        };
+        let mut payload = packet.split_off(Self::HEADER_LEN);
+        payload.truncate(payload_len - Self::HEADER_LEN);
+        let res = self
+            .inner
+            .decrypt_in_place(&Nonce::from(nonce), &packet[..], &mut payload);
+        if res.is_err() {
+            // Packet is left as it was
+            payload.extend_from_slice(&counter);
+            packet.unsplit(payload);
+            return Err(DecryptError::Unauthenticated);
+        }
+
+        packet.unsplit(payload);
+        self.replay_window.update(u64::from_le_bytes(counter));
+
+        Ok(())
+    }
+}
+
+/// Passes packets as is, used by unencrypted streams.
+pub struct NoCipher;
+
+impl AudioCipher for NoCipher {
+    fn decrypt(&mut self, _: &mut BytesMut) -> Result<(), DecryptError> {
+        Ok(())
+    }
+}
+
+impl VideoCipher for NoCipher {
+    fn decrypt(&mut self, _: [u8; 128], _: &mut BytesMut) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use bytes::BytesMut;
+    use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit as _, Nonce, aead::AeadInOut as _};
+
+    use super::{
+        AesGcmAudioCipher, AesGcmVideoCipher, AesVideoCipher, AudioCipher, ChachaAudioCipher,
+        ChachaVideoCipher, ControlCipher, DecryptError, VideoCipher,
+    };
+
+    const AUDIO_KEY: [u8; 32] = [7; 32];
+
+    fn chacha_audio_packet(counter: u64, frame: &[u8]) -> BytesMut {
+        let counter = counter.to_le_bytes();
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&counter);
+
+        let mut rtp = BytesMut::from(&[0x80, 0x60, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3][..]);
+        let mut payload = BytesMut::from(frame);
+        ChaCha20Poly1305::new(&Key::from(AUDIO_KEY))
+            .encrypt_in_place(&Nonce::from(nonce), &rtp[4..12], &mut payload)
+            .unwrap();
+        rtp.unsplit(payload);
+        rtp.extend_from_slice(&counter);
+        rtp
+    }
 
-    use super::{AesVideoCipher, VideoCipher};
+    #[test]
+    fn test_audio_replay() {
+        const FRAME: &[u8] = b"audio frame";
+
+        let packet = chacha_audio_packet(5, FRAME);
+        let mut cipher = ChachaAudioCipher::from_key(AUDIO_KEY.into()).with_replay_protection();
+
+        let mut first = packet.clone();
+        cipher.decrypt(&mut first).unwrap();
+        assert_eq!(&first[12..], FRAME);
+
+        let mut replayed = packet.clone();
+        assert_eq!(cipher.decrypt(&mut replayed), Err(DecryptError::Replayed));
+        assert_eq!(replayed, packet);
+
+        // Fresh counter doesn't make the replay pass, nonce is authenticated
+        let mut replayed = packet.clone();
+        let counter_at = replayed.len() - 8;
+        replayed[counter_at..].copy_from_slice(&6u64.to_le_bytes());
+        assert_eq!(
+            cipher.decrypt(&mut replayed),
+            Err(DecryptError::Unauthenticated)
+        );
+
+        // Rejected packet didn't take its counter
+        let mut next = chacha_audio_packet(6, b"next frame");
+        cipher.decrypt(&mut next).unwrap();
+        assert_eq!(&next[12..], b"next frame");
+    }
+
+    // Shared secret of the session for control vectors, stream connection id is 42
+    const CONTROL_SECRET: [u8; 32] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d,
+        0x1e, 0x1f,
+    ];
+
+    #[test]
+    fn test_control_decrypt() {
+        const ENCRYPTED: &[u8] = &[
+            0x80, 0xd4, 0x00, 0x07, 0xf2, 0xc9, 0x5e, 0x31, 0xed, 0x9b, 0xe5, 0x1f, 0xc4, 0x8f,
+            0x56, 0xe2, 0xfe, 0xfa, 0xd2, 0xc7, 0xdb, 0xe5, 0xe7, 0x02, 0xfc, 0x01, 0x19, 0x02,
+            0x29, 0xa6, 0xb7, 0xbd, 0xf4, 0x8a, 0x9c, 0x45, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        const PLAIN: &[u8] = &[
+            0x80, 0xd4, 0x00, 0x07, 0x00, 0x00, 0xa1, 0xb2, 0xe3, 0xf4, 0xa5, 0xb6, 0x00, 0xc0,
+            0xff, 0xee, 0x00, 0x00, 0xa1, 0xb2,
+        ];
+
+        let mut cipher = ControlCipher::new(&CONTROL_SECRET, 42);
+        let mut packet = BytesMut::from(ENCRYPTED);
+        cipher.decrypt(&mut packet).unwrap();
+        assert_eq!(packet, PLAIN);
+
+        let mut replayed = BytesMut::from(ENCRYPTED);
+        assert_eq!(cipher.decrypt(&mut replayed), Err(DecryptError::Replayed));
+
+        let mut tampered = BytesMut::from(ENCRYPTED);
+        tampered[3] ^= 1;
+        assert_eq!(
+            ControlCipher::new(&CONTROL_SECRET, 42).decrypt(&mut tampered),
+            Err(DecryptError::Unauthenticated)
+        );
+        tampered[3] ^= 1;
+        assert_eq!(tampered, ENCRYPTED);
+    }
+
+    #[test]
+    fn test_aes_gcm_audio() {
+        const IV: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+        const ENCRYPTED: &[u8] = &[
+            0x80, 0x60, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03, 0x1f, 0xbd,
+            0x68, 0xf1, 0x85, 0x6d, 0x5b, 0xb3, 0x1c, 0x70, 0xd5, 0x12, 0x73, 0xc4, 0x34, 0x97,
+            0x76, 0x39, 0xde, 0x9b, 0x3a, 0xb4, 0xf5, 0x99, 0xa6, 0x5d, 0x24, 0x05, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+
+        let mut cipher = AesGcmAudioCipher::new([0x11; 16].into(), IV).with_replay_protection();
+        let mut packet = BytesMut::from(ENCRYPTED);
+        cipher.decrypt(&mut packet).unwrap();
+        assert_eq!(&packet[..12], &ENCRYPTED[..12]);
+        assert_eq!(&packet[12..], b"audio frame");
+
+        let mut replayed = BytesMut::from(ENCRYPTED);
+        assert_eq!(cipher.decrypt(&mut replayed), Err(DecryptError::Replayed));
+
+        let mut tampered = BytesMut::from(ENCRYPTED);
+        tampered[4] ^= 1;
+        assert_eq!(
+            AesGcmAudioCipher::new([0x11; 16].into(), IV).decrypt(&mut tampered),
+            Err(DecryptError::Unauthenticated)
+        );
+    }
+
+    #[test]
+    fn test_aes_gcm_video() {
+        const FIRST: &[u8] = &[
+            0xcc, 0x3a, 0xb0, 0x52, 0xc2, 0x23, 0x99, 0x55, 0x09, 0x2b, 0x67, 0x46, 0xf6, 0x8c,
+            0x59, 0x53, 0x18, 0x7a, 0xa3, 0xa7, 0xbd, 0x2b, 0x69, 0x39, 0x5f, 0x40, 0x5e,
+        ];
+        const SECOND: &[u8] = &[
+            0xdc, 0x01, 0x3b, 0x1d, 0x1a, 0xe5, 0xc9, 0xf1, 0xf9, 0x12, 0x93, 0xfc, 0xdf, 0xae,
+            0x70, 0xc6, 0x25, 0xcd, 0xa3, 0x63, 0x45, 0xa6, 0x42, 0xd3, 0x51, 0x76, 0x86, 0xa4,
+        ];
+
+        let mut cipher = AesGcmVideoCipher::from_key_and_id([0x11; 16].into(), 7);
+        let mut first = BytesMut::from(FIRST);
+        cipher.decrypt([1; 128], &mut first).unwrap();
+        assert_eq!(&first[..], b"first frame");
+
+        let mut second = BytesMut::from(SECOND);
+        cipher.decrypt([1; 128], &mut second).unwrap();
+        assert_eq!(&second[..], b"second frame");
+    }
+
+    #[test]
+    fn test_chacha_video() {
+        // Computed with Python's `cryptography`, shared secret is 32x 0x42
+        const FIRST: &[u8] = &[
+            0x6b, 0x31, 0xcb, 0x1f, 0x47, 0xfe, 0xb8, 0x87, 0x1b, 0xc8, 0x82, 0x7d, 0x3b, 0x02,
+            0x9e, 0x1e, 0x65, 0xa0, 0xd0, 0x29, 0x54, 0xdb, 0xe4, 0xfd, 0x5a, 0xa1, 0x5c,
+        ];
+        const SECOND: &[u8] = &[
+            0x39, 0x2a, 0xb6, 0x16, 0xff, 0xb3, 0xaa, 0xaf, 0xb1, 0x38, 0xb4, 0x7c, 0x2c, 0x5c,
+            0xd1, 0x2e, 0x14, 0x4c, 0x9e, 0xa1, 0x0d, 0xbb, 0x49, 0xb2, 0x27, 0xb1, 0x0e, 0x2a,
+        ];
+
+        // Nonce follows the frame count, so frames can't be skipped
+        let mut cipher = ChachaVideoCipher::from_secret_and_id(&[0x42; 32], 7);
+        let mut second = BytesMut::from(SECOND);
+        assert!(cipher.decrypt([1; 128], &mut second).is_err());
+
+        let mut first = BytesMut::from(FIRST);
+        cipher.decrypt([1; 128], &mut first).unwrap();
+        assert_eq!(&first[..], b"first frame");
+
+        let mut second = BytesMut::from(SECOND);
+        cipher.decrypt([1; 128], &mut second).unwrap();
+        assert_eq!(&second[..], b"second frame");
+    }
 
     #[test]
     fn test_video_decipher() {
@@ -250,7 +692,7 @@ mod tests {
         let mut input = (0..1025usize)
             .map(|x| (x % 255) as u8)
             .collect::<BytesMut>();
-        let mut cipher = AesVideoCipher::from_key_and_id([1; 16], 1000);
+        let mut cipher = AesVideoCipher::from_key_and_id([1; 16].into(), 1000);
 
         cipher.decrypt([0; _], &mut input).unwrap();
 
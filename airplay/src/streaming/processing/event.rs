@@ -0,0 +1,279 @@
+use std::io;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use plist::Value;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{
+    pairing::{
+        SessionKey,
+        homekit::codec::{HAPDecoder, HAPEncoder},
+    },
+    playback::Event,
+};
+
+const HEAD_END: &[u8] = b"\r\n\r\n";
+/// Messages of the event channel are small, anything larger is garbage
+const MAX_HEAD_LEN: usize = 16 * 1024;
+const MAX_BODY_LEN: usize = 1024 * 1024;
+
+/// RTSP-style message of the event channel, mostly responses of the sender to our commands.
+/// AirPlay 1 mirroring connection speaks the same, so it's parsed here too.
+#[derive(Debug)]
+pub struct Message {
+    pub start_line: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+}
+
+impl Message {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn plist(&self) -> Option<Value> {
+        if !self.header("Content-Type")?.contains("plist") {
+            return None;
+        }
+
+        Value::from_reader(io::Cursor::new(&self.body)).ok()
+    }
+}
+
+/// Event channel is HAP-encrypted if the session is paired with HomeKit.
+pub struct EventCodec {
+    hap: Option<(HAPEncoder, HAPDecoder)>,
+    decrypted: BytesMut,
+    cseq: u32,
+}
+
+impl EventCodec {
+    pub fn new(session_key: Option<&SessionKey>) -> Self {
+        const SALT: &[u8] = b"Events-Salt";
+
+        let hap = session_key.map(|key| {
+            (
+                HAPEncoder::with_salt_and_info(
+                    &key.key_material,
+                    SALT,
+                    b"Events-Read-Encryption-Key",
+                ),
+                HAPDecoder::with_salt_and_info(
+                    &key.key_material,
+                    SALT,
+                    b"Events-Write-Encryption-Key",
+                ),
+            )
+        });
+
+        Self {
+            hap,
+            decrypted: BytesMut::new(),
+            cseq: 0,
+        }
+    }
+}
+
+impl Decoder for EventCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some((_, decoder)) = &mut self.hap else {
+            return parse(src);
+        };
+
+        while let Some(block) = decoder.decode(src)? {
+            self.decrypted.extend_from_slice(&block);
+        }
+        parse(&mut self.decrypted)
+    }
+}
+
+impl Encoder<&Event> for EventCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, event: &Event, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let mut body = Vec::new();
+        Value::Dictionary(event.to_plist())
+            .to_writer_binary(&mut body)
+            .map_err(io::Error::other)?;
+
+        self.cseq += 1;
+        let head = format!(
+            "POST /command RTSP/1.0\r\n\
+             CSeq: {}\r\n\
+             Content-Type: application/x-apple-binary-plist\r\n\
+             Content-Length: {}\r\n\r\n",
+            self.cseq,
+            body.len()
+        );
+
+        let mut request = BytesMut::with_capacity(head.len() + body.len());
+        request.put_slice(head.as_bytes());
+        request.put_slice(&body);
+
+        match &mut self.hap {
+            Some((encoder, _)) => encoder.encode(request, dst),
+            None => {
+                dst.put(request);
+                Ok(())
+            }
+        }
+    }
+}
+
+pub(crate) fn parse(src: &mut BytesMut) -> io::Result<Option<Message>> {
+    let Some(head_len) = src.windows(HEAD_END.len()).position(|w| w == HEAD_END) else {
+        if src.len() > MAX_HEAD_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message head is too long",
+            ));
+        }
+        return Ok(None);
+    };
+
+    let head = str::from_utf8(&src[..head_len])
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let mut lines = head.split("\r\n");
+    let start_line = lines.next().unwrap_or_default().to_string();
+    let headers = lines
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            Some((key.trim().to_string(), value.trim().to_string()))
+        })
+        .collect::<Vec<_>>();
+
+    let body_len = headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("Content-Length"))
+        .map(|(_, value)| value.parse::<usize>())
+        .transpose()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+        .unwrap_or_default();
+    if body_len > MAX_BODY_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message body is too long",
+        ));
+    }
+
+    let start = head_len + HEAD_END.len();
+    let Some(end) = start.checked_add(body_len) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message length overflows",
+        ));
+    };
+    if src.len() < end {
+        src.reserve(end - src.len());
+        return Ok(None);
+    }
+
+    let body = src.split_to(end).split_off(start).freeze();
+    Ok(Some(Message {
+        start_line,
+        headers,
+        body,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use plist::{Dictionary, Value};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::EventCodec;
+    use crate::{
+        pairing::{
+            SessionKey,
+            homekit::codec::{HAPDecoder, HAPEncoder},
+        },
+        playback::Event,
+    };
+
+    fn session_key() -> SessionKey {
+        SessionKey {
+            key_material: vec![0x42; 32].into(),
+            upgrade_channel: true,
+            pairing_id: None,
+        }
+    }
+
+    #[test]
+    fn encrypted_command() {
+        let key = session_key();
+        let mut codec = EventCodec::new(Some(&key));
+
+        let mut info = Dictionary::new();
+        info.insert("volume".to_string(), Value::Real(-15.0));
+        let mut encrypted = BytesMut::new();
+        codec
+            .encode(&Event::UpdateInfo(info.clone()), &mut encrypted)
+            .unwrap();
+
+        // Sender decrypts with the keys swapped
+        let mut sender_decoder = HAPDecoder::with_salt_and_info(
+            &key.key_material,
+            b"Events-Salt",
+            b"Events-Read-Encryption-Key",
+        );
+        let mut request = sender_decoder.decode(&mut encrypted).unwrap().unwrap();
+        let request = super::parse(&mut request).unwrap().unwrap();
+        assert_eq!(request.start_line, "POST /command RTSP/1.0");
+        assert_eq!(request.header("CSeq"), Some("1"));
+
+        let plist = request.plist().and_then(Value::into_dictionary).unwrap();
+        assert_eq!(
+            plist.get("type").and_then(Value::as_string),
+            Some("updateInfo")
+        );
+        assert_eq!(plist.get("value"), Some(&Value::Dictionary(info)));
+
+        let mut sender_encoder = HAPEncoder::with_salt_and_info(
+            &key.key_material,
+            b"Events-Salt",
+            b"Events-Write-Encryption-Key",
+        );
+        let mut response = BytesMut::new();
+        sender_encoder
+            .encode(b"RTSP/1.0 200 OK\r\nCSeq: 1\r\n\r\n", &mut response)
+            .unwrap();
+
+        // Split in the middle of the block
+        let rest = response.split_off(response.len() / 2);
+        assert!(codec.decode(&mut response).unwrap().is_none());
+        response.unsplit(rest);
+        let response = codec.decode(&mut response).unwrap().unwrap();
+        assert_eq!(response.start_line, "RTSP/1.0 200 OK");
+        assert!(response.body.is_empty());
+    }
+
+    #[test]
+    fn plain_with_body() {
+        let mut codec = EventCodec::new(None);
+        let mut data = BytesMut::from(&b"RTSP/1.0 200 OK\r\nContent-Length: 4\r\n\r\nab"[..]);
+
+        assert!(codec.decode(&mut data).unwrap().is_none());
+        data.extend_from_slice(b"cd");
+        let message = codec.decode(&mut data).unwrap().unwrap();
+        assert_eq!(&message.body[..], b"abcd");
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn oversized_body() {
+        let mut data =
+            BytesMut::from(&b"RTSP/1.0 200 OK\r\nContent-Length: 18446744073709551615\r\n\r\n"[..]);
+        assert!(super::parse(&mut data).is_err());
+
+        let mut data = BytesMut::from(&b"RTSP/1.0 200 OK\r\nContent-Length: 1048577\r\n\r\n"[..]);
+        assert!(super::parse(&mut data).is_err());
+        assert!(data.capacity() < super::MAX_BODY_LEN);
+    }
+}
@@ -0,0 +1,160 @@
+use bytes::Buf;
+
+/// Header that precedes every packet of the mirroring stream.
+///
+/// Layout (all values are little-endian):
+/// - `0..4`: payload length
+/// - `4`: packet type
+/// - `5`: flags
+/// - `6..8`: type-specific option
+/// - `8..16`: sender's NTP timestamp
+/// - `40..48`, `56..64`: source and destination dimensions as `f32` (codec packets only)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VideoHeader {
+    pub payload_len: u32,
+    pub kind: HeaderKind,
+    pub flags: HeaderFlags,
+    pub option: u16,
+    pub ntp_timestamp: u64,
+    pub dimensions: Option<Dimensions>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderKind {
+    /// Encrypted frame data
+    Payload,
+    /// AVCC/HVCC decoder configuration
+    Codec,
+    /// Keepalive sent roughly once a second, no payload
+    Heartbeat,
+    /// Unencrypted property list
+    Plist,
+    Other(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderFlags(pub u8);
+
+impl HeaderFlags {
+    /// Noted as a keyframe marker by UxPlay and others, it says nothing about the codec
+    const KEYFRAME: u8 = 0x10;
+
+    pub fn is_keyframe(self) -> bool {
+        self.0 & Self::KEYFRAME != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dimensions {
+    pub source_width: f32,
+    pub source_height: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl HeaderKind {
+    /// Only frame data is encrypted, everything else is sent as is.
+    pub fn is_encrypted(self) -> bool {
+        matches!(self, Self::Payload)
+    }
+}
+
+impl VideoHeader {
+    pub const LEN: usize = 128;
+
+    pub fn parse(header: &[u8; Self::LEN]) -> Self {
+        let mut ptr = &header[..];
+        let payload_len = ptr.get_u32_le();
+        let kind = match ptr.get_u8() {
+            0 => HeaderKind::Payload,
+            1 => HeaderKind::Codec,
+            2 => HeaderKind::Heartbeat,
+            5 => HeaderKind::Plist,
+            other => HeaderKind::Other(other),
+        };
+        let flags = HeaderFlags(ptr.get_u8());
+        let option = ptr.get_u16_le();
+        let ntp_timestamp = ptr.get_u64_le();
+
+        let dimensions = matches!(kind, HeaderKind::Codec).then(|| {
+            let float = |offset: usize| (&header[offset..]).get_f32_le();
+            Dimensions {
+                source_width: float(40),
+                source_height: float(44),
+                width: float(56),
+                height: float(60),
+            }
+        });
+
+        Self {
+            payload_len,
+            kind,
+            flags,
+            option,
+            ntp_timestamp,
+            dimensions,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dimensions, HeaderKind, VideoHeader};
+
+    fn header(prefix: &[u8]) -> [u8; VideoHeader::LEN] {
+        let mut header = [0u8; VideoHeader::LEN];
+        header[..prefix.len()].copy_from_slice(prefix);
+        header
+    }
+
+    #[test]
+    fn parse_payload() {
+        let header = header(&[
+            0x1a, 0x3c, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x2f, 0x0c,
+            0x00, 0x00,
+        ]);
+        let header = VideoHeader::parse(&header);
+
+        assert_eq!(header.payload_len, 0x3c1a);
+        assert_eq!(header.kind, HeaderKind::Payload);
+        assert!(header.kind.is_encrypted());
+        assert!(header.flags.is_keyframe());
+        assert_eq!(header.dimensions, None);
+        assert_eq!(header.ntp_timestamp, 0x0c2f_8000_0000);
+    }
+
+    #[test]
+    fn parse_codec() {
+        let mut header = header(&[0x23, 0x00, 0x00, 0x00, 0x01, 0x00, 0x16, 0x01]);
+        header[40..44].copy_from_slice(&2532f32.to_le_bytes());
+        header[44..48].copy_from_slice(&1170f32.to_le_bytes());
+        header[56..60].copy_from_slice(&1920f32.to_le_bytes());
+        header[60..64].copy_from_slice(&887f32.to_le_bytes());
+        let header = VideoHeader::parse(&header);
+
+        assert_eq!(header.kind, HeaderKind::Codec);
+        assert!(!header.kind.is_encrypted());
+        assert!(!header.flags.is_keyframe());
+        assert_eq!(header.option, 0x0116);
+        assert_eq!(
+            header.dimensions,
+            Some(Dimensions {
+                source_width: 2532.0,
+                source_height: 1170.0,
+                width: 1920.0,
+                height: 887.0,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_heartbeat_and_unknown() {
+        let heartbeat = VideoHeader::parse(&header(&[0, 0, 0, 0, 0x02]));
+        assert_eq!(heartbeat.kind, HeaderKind::Heartbeat);
+        assert_eq!(heartbeat.payload_len, 0);
+
+        let unknown = VideoHeader::parse(&header(&[0, 0, 0, 0, 0x07]));
+        assert_eq!(unknown.kind, HeaderKind::Other(7));
+        assert!(!unknown.kind.is_encrypted());
+    }
+}
@@ -1,24 +1,31 @@
 use std::{io, net::IpAddr};
 
-use bytes::Buf;
+use bytes::BytesMut;
 use tokio::{
-    io::AsyncReadExt,
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream, UdpSocket},
+    sync::mpsc,
 };
+use tokio_util::codec::{Decoder, Encoder};
 use tracing::Instrument;
 
+use self::header::HeaderKind;
 use super::EncryptionMaterial;
 use crate::{
     crypto::{AesIv128, AesKey128, ChaCha20Poly1305Key},
     pairing::SessionKey,
     playback::{
+        Event,
         audio::{AudioPacket, AudioStream},
-        video::{PacketKind, VideoPacket, VideoStream},
+        video::{MirroringEvent, PacketKind, VideoPacket, VideoStream},
     },
 };
 
-mod crypto;
+pub(crate) mod crypto;
+pub(crate) mod event;
+pub(crate) mod header;
 mod memory;
+mod replay;
 
 #[derive(Debug)]
 pub enum Encryption {
@@ -34,6 +41,12 @@ pub enum Encryption {
         iv: AesIv128,
         stream_connection_id: Option<u64>,
     },
+    AesGcm {
+        key: AesKey128,
+        iv: AesIv128,
+        stream_connection_id: Option<u64>,
+    },
+    None,
 }
 
 impl TryFrom<EncryptionMaterial> for Encryption {
@@ -45,11 +58,20 @@ impl TryFrom<EncryptionMaterial> for Encryption {
         } else if let Some(key) = value.aeskey
             && let Some(iv) = value.aesiv
         {
-            Ok(Encryption::Legacy {
-                key,
-                iv,
-                stream_connection_id: value.stream_connection_id,
-            })
+            let stream_connection_id = value.stream_connection_id;
+            if value.aes_gcm {
+                Ok(Encryption::AesGcm {
+                    key,
+                    iv,
+                    stream_connection_id,
+                })
+            } else {
+                Ok(Encryption::Legacy {
+                    key,
+                    iv,
+                    stream_connection_id,
+                })
+            }
         } else if let Some(key) = value.session_key
             && let Some(stream_connection_id) = value.stream_connection_id
         {
@@ -57,6 +79,8 @@ impl TryFrom<EncryptionMaterial> for Encryption {
                 key,
                 stream_connection_id,
             })
+        } else if value.allow_unencrypted {
+            Ok(Encryption::None)
         } else {
             Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
@@ -66,12 +90,58 @@ impl TryFrom<EncryptionMaterial> for Encryption {
     }
 }
 
-#[tracing::instrument(level = "DEBUG")]
-pub async fn event_processor(listener: TcpListener) {
-    let mut buf = [0; 16 * 1024];
-    while let Ok((mut stream, remote_addr)) = listener.accept().await {
-        while let Ok(len @ 1..) = stream.read(&mut buf).await {
-            tracing::trace!(%len, %remote_addr, "event data");
+impl Encryption {
+    /// Tag and nonce which sealed audio packets end with, other ciphers add nothing.
+    pub(crate) fn audio_trailer_len(&self) -> usize {
+        match self {
+            Self::ChaCha { .. } | Self::HomeKit { .. } | Self::AesGcm { .. } => 16 + 8,
+            Self::Legacy { .. } | Self::None => 0,
+        }
+    }
+}
+
+#[tracing::instrument(level = "DEBUG", skip_all)]
+pub async fn event_processor(
+    listener: TcpListener,
+    session_key: Option<SessionKey>,
+    mut events: mpsc::Receiver<Event>,
+) {
+    let mut buf = BytesMut::new();
+    while let Ok((mut tcp_stream, remote_addr)) = listener.accept().await {
+        tracing::debug!(%remote_addr, "event channel connected");
+
+        let mut codec = event::EventCodec::new(session_key.as_ref());
+        let (mut reader, mut writer) = tcp_stream.split();
+        buf.clear();
+
+        let res: io::Result<()> = async {
+            loop {
+                tokio::select! {
+                    len = reader.read_buf(&mut buf) => {
+                        if len? == 0 {
+                            return Ok(());
+                        }
+                        while let Some(message) = codec.decode(&mut buf)? {
+                            tracing::debug!(
+                                start_line = %message.start_line,
+                                plist = ?message.plist(),
+                                "event channel message"
+                            );
+                        }
+                    }
+                    Some(event) = events.recv() => {
+                        let mut data = BytesMut::new();
+                        codec.encode(&event, &mut data)?;
+                        writer.write_all(&data).await?;
+                        tracing::debug!(?event, "event sent");
+                    }
+                }
+            }
+        }
+        .await;
+
+        if let Err(err) = res {
+            tracing::warn!(%err, %remote_addr, "event channel closed");
         }
     }
 }
@@ -83,10 +153,9 @@ pub async fn audio_buffered_processor(
     audio_buf_size: u32,
     encryption: Encryption,
 ) -> io::Result<()> {
-    const TRAILER_LEN: usize = 24;
-
+    let trailer_len = encryption.audio_trailer_len();
     let mut audio_buf = memory::BytesHunk::new(audio_buf_size as usize);
-    let cipher = build_audio_cipher(&encryption);
+    let mut cipher = build_audio_cipher(&encryption, false);
 
     loop {
         async {
@@ -94,7 +163,7 @@ pub async fn audio_buffered_processor(
             // 2 is pkt_len field size itself
             let pkt_len: usize = pkt_len.saturating_sub(2).into();
 
-            if pkt_len < AudioPacket::HEADER_LEN + TRAILER_LEN {
+            if pkt_len < AudioPacket::HEADER_LEN + trailer_len {
                 return Err(io::Error::other("malformed buffered stream"));
             }
 
@@ -128,7 +197,8 @@ pub async fn audio_realtime_processor(
 ) -> io::Result<()> {
     let mut pkt_buf = [0u8; 16 * 1024];
     let mut audio_buf = memory::BytesHunk::new(audio_buf_size as usize);
-    let cipher = build_audio_cipher(&encryption);
+    let mut cipher = build_audio_cipher(&encryption, true);
+    let mut replayed = 0u64;
 
     loop {
         async {
@@ -143,10 +213,18 @@ pub async fn audio_realtime_processor(
                     rtp.copy_from_slice(&pkt_buf[..pkt_len]);
                     tracing::trace!(%pkt_len, "packet read");
 
-                    if cipher.decrypt(&mut rtp).is_ok() {
-                        tracing::trace!("packet decrypted");
-                    } else {
-                        tracing::warn!("packet decryption failed");
+                    match cipher.decrypt(&mut rtp) {
+                        Ok(()) => tracing::trace!("packet decrypted"),
+                        Err(crypto::DecryptError::Replayed) => {
+                            replayed += 1;
+                            tracing::debug!(%replayed, "replayed packet dropped");
+                            return Ok(());
+                        }
+                        // Forged or corrupted, it's never passed to the stream
+                        Err(err) => {
+                            tracing::warn!(%err, "packet decryption failed");
+                            return Ok(());
+                        }
                     }
 
                     stream.on_data(AudioPacket { rtp });
@@ -163,19 +241,41 @@ pub async fn audio_realtime_processor(
     }
 }
 
-#[tracing::instrument(level = "DEBUG", err)]
-pub async fn control_processor(_expected_remote_addr: IpAddr, socket: UdpSocket) -> io::Result<()> {
-    const BUF_SIZE: usize = 16 * 1024;
-
-    let mut buf = [0u8; BUF_SIZE];
+/// Sync packets and resend replies of realtime audio aren't used yet, they're only checked
+/// and logged. `cipher` is set by `ControlChannelEncrypt` feature.
+#[tracing::instrument(level = "DEBUG", err, skip(cipher))]
+pub async fn control_processor(
+    expected_remote_addr: IpAddr,
+    socket: UdpSocket,
+    mut cipher: Option<crypto::ControlCipher>,
+) -> io::Result<()> {
+    let mut buf = [0u8; 16 * 1024];
     loop {
-        let _pkt_len = socket.recv(&mut buf).await?;
+        let (pkt_len, remote_addr) = socket.recv_from(&mut buf).await?;
+        if expected_remote_addr != remote_addr.ip() {
+            tracing::debug!(%remote_addr, "skip invalid connection");
+            continue;
+        }
+        if pkt_len < crypto::ControlCipher::HEADER_LEN {
+            tracing::warn!(%pkt_len, "malformed control packet");
+            continue;
+        }
+
+        let mut pkt = BytesMut::from(&buf[..pkt_len]);
+        if let Some(cipher) = &mut cipher
+            && let Err(err) = cipher.decrypt(&mut pkt)
+        {
+            tracing::warn!(%err, "control packet decryption failed");
+            continue;
+        }
+
+        tracing::trace!(kind = pkt[1] & 0x7F, %pkt_len, "control packet");
     }
 }
 
-#[tracing::instrument(level = "DEBUG", skip(stream))]
+#[tracing::instrument(level = "DEBUG", skip(reader, stream))]
 pub async fn video_processor(
-    mut tcp_stream: TcpStream,
+    mut reader: impl AsyncRead + Unpin,
     stream: &impl VideoStream,
     video_buf_size: u32,
     encryption: Encryption,
@@ -185,42 +285,61 @@ pub async fn video_processor(
 
     loop {
         async {
-            let mut header = [0u8; _];
-            tcp_stream.read_exact(&mut header).await?;
-
-            let mut ptr = &header[..];
-            let payload_len = ptr.get_u32_le();
-            let mut payload = video_buf.allocate_buf(payload_len as usize);
-            tcp_stream.read_exact(&mut payload).await?;
-            let kind = match ptr.get_u16_le() {
-                1 => {
-                    if payload.len() >= 8 && &payload[4..8] == b"hvc1" {
-                        PacketKind::HvcC
-                    } else {
-                        PacketKind::AvcC
-                    }
-                },
-                0 | 4096 => PacketKind::Payload,
-                5 => PacketKind::Plist,
-                other => PacketKind::Other(other),
-            };
-            let unknown_field = ptr.get_u16_le();
-            let timestamp = ptr.get_u64_le();
+            let mut header = [0u8; header::VideoHeader::LEN];
+            reader.read_exact(&mut header).await?;
+
+            let parsed = header::VideoHeader::parse(&header);
+            let mut payload = video_buf.allocate_buf(parsed.payload_len as usize);
+            reader.read_exact(&mut payload).await?;
+            if let Some(dim) = parsed.dimensions {
+                tracing::debug!(
+                    source_width = dim.source_width,
+                    source_height = dim.source_height,
+                    width = dim.width,
+                    height = dim.height,
+                    "codec dimensions"
+                );
+            }
+            let kind = video_packet_kind(&parsed, &payload);
+            tracing::trace!(
+                ?kind,
+                flags = parsed.flags.0,
+                option = parsed.option,
+                timestamp = parsed.ntp_timestamp,
+                payload_len = parsed.payload_len,
+                "packet read"
+            );
+
+            if matches!(kind, PacketKind::Heartbeat) {
+                return Ok(());
+            }
 
             let mut pkt = VideoPacket {
                 kind,
-                timestamp,
+                timestamp: parsed.ntp_timestamp,
+                keyframe: parsed.flags.is_keyframe(),
                 payload,
             };
-            tracing::trace!(?kind, %timestamp, unknown=%unknown_field, %payload_len, "packet read");
 
-            // Only payload need to be decrypted
-            // TODO: Other(_) too?
-            if matches!(kind, PacketKind::Payload) {
+            if parsed.kind.is_encrypted() {
                 if cipher.decrypt(header, &mut pkt.payload).is_ok() {
                     tracing::trace!("packet decrypted");
                 } else {
+                    // Still ciphertext, maybe forged, so it's never passed to the stream
                     tracing::warn!("packet decryption failed");
+                    return Ok(());
+                }
+            }
+
+            if matches!(kind, PacketKind::Plist) {
+                match MirroringEvent::from_plist(&pkt.payload) {
+                    Ok(event) => {
+                        tracing::trace!(?event, "mirroring event");
+                        stream.on_event(event);
+                        tokio::task::consume_budget().await;
+                        return Ok(());
+                    }
+                    Err(err) => tracing::warn!(%err, "malformed plist packet"),
                 }
             }
 
@@ -234,22 +353,66 @@ pub async fn video_processor(
     }
 }
 
-fn build_audio_cipher(encryption: &Encryption) -> Box<dyn crypto::AudioCipher + Send + Sync> {
+/// Codec packets are told apart by the tag of the configuration record only, flags of the
+/// header don't tell the codec.
+pub(crate) fn video_packet_kind(header: &header::VideoHeader, payload: &[u8]) -> PacketKind {
+    match header.kind {
+        HeaderKind::Codec => {
+            if payload.get(4..8) == Some(&b"hvc1"[..]) {
+                PacketKind::HvcC
+            } else {
+                PacketKind::AvcC
+            }
+        }
+        HeaderKind::Payload => PacketKind::Payload,
+        HeaderKind::Heartbeat => PacketKind::Heartbeat,
+        HeaderKind::Plist => PacketKind::Plist,
+        HeaderKind::Other(other) => PacketKind::Other(other.into()),
+    }
+}
+
+/// Only datagrams can be replayed one by one, so protection is needed for realtime audio only.
+pub(crate) fn build_audio_cipher(
+    encryption: &Encryption,
+    replay_protection: bool,
+) -> Box<dyn crypto::AudioCipher + Send + Sync> {
+    let chacha = |cipher: crypto::ChachaAudioCipher| -> Box<dyn crypto::AudioCipher + Send + Sync> {
+        if replay_protection {
+            Box::new(cipher.with_replay_protection())
+        } else {
+            Box::new(cipher)
+        }
+    };
+
     match encryption {
-        Encryption::ChaCha { key } => Box::new(crypto::ChachaAudioCipher::from_key(*key)),
+        Encryption::ChaCha { key } => chacha(crypto::ChachaAudioCipher::from_key(key.clone())),
         Encryption::HomeKit {
             key,
             stream_connection_id,
-        } => Box::new(crypto::ChachaAudioCipher::from_secret_and_id(
+        } => chacha(crypto::ChachaAudioCipher::from_secret_and_id(
             &key.key_material,
             *stream_connection_id,
         )),
-        Encryption::Legacy { key, iv, .. } => Box::new(crypto::AesAudioCipher::new(*key, *iv)),
+        Encryption::Legacy { key, iv, .. } => {
+            Box::new(crypto::AesAudioCipher::new(key.clone(), *iv))
+        }
+        Encryption::AesGcm { key, iv, .. } => {
+            let cipher = crypto::AesGcmAudioCipher::new(key.clone(), *iv);
+            if replay_protection {
+                Box::new(cipher.with_replay_protection())
+            } else {
+                Box::new(cipher)
+            }
+        }
+        Encryption::None => Box::new(crypto::NoCipher),
     }
 }
 
-fn build_video_cipher(encryption: &Encryption) -> Box<dyn crypto::VideoCipher + Send + Sync> {
+pub(crate) fn build_video_cipher(
+    encryption: &Encryption,
+) -> Box<dyn crypto::VideoCipher + Send + Sync> {
     match encryption {
+        Encryption::ChaCha { key } => Box::new(crypto::ChachaVideoCipher::from_key(key.clone())),
         Encryption::HomeKit {
             key,
             stream_connection_id,
@@ -262,9 +425,227 @@ fn build_video_cipher(encryption: &Encryption) -> Box<dyn crypto::VideoCipher +
             stream_connection_id: Some(stream_connection_id),
             ..
         } => Box::new(crypto::AesVideoCipher::from_key_and_id(
-            *key,
+            key.clone(),
             *stream_connection_id,
         )),
-        _ => unreachable!(),
+        Encryption::Legacy {
+            key,
+            iv,
+            stream_connection_id: None,
+        } => Box::new(crypto::AesVideoCipher::new(key.clone(), *iv)),
+        Encryption::AesGcm {
+            key,
+            stream_connection_id: Some(stream_connection_id),
+            ..
+        } => Box::new(crypto::AesGcmVideoCipher::from_key_and_id(
+            key.clone(),
+            *stream_connection_id,
+        )),
+        Encryption::AesGcm {
+            key,
+            iv,
+            stream_connection_id: None,
+        } => Box::new(crypto::AesGcmVideoCipher::new(key.clone(), *iv)),
+        Encryption::None => Box::new(crypto::NoCipher),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, io, sync::Mutex};
+
+    use bytes::BytesMut;
+    use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit as _, Nonce, aead::AeadInOut as _};
+    use tokio::{io::AsyncWriteExt as _, net::UdpSocket};
+
+    use super::{
+        Encryption, EncryptionMaterial, audio_realtime_processor, header::VideoHeader,
+        video_packet_kind, video_processor,
+    };
+    use crate::{
+        crypto::Secret,
+        pairing::SessionKey,
+        playback::{
+            Stream,
+            audio::AudioPacket,
+            video::{PacketKind, VideoPacket, VideoStream},
+        },
+    };
+
+    struct Packets<T>(Mutex<Vec<T>>);
+
+    impl<T: Send + 'static> Stream for Packets<T> {
+        type Content = T;
+
+        fn on_data(&self, content: Self::Content) {
+            self.0.lock().unwrap().push(content);
+        }
+
+        fn on_ok(self) {}
+
+        fn on_err(self, _: Box<dyn Error>) {}
+    }
+
+    impl VideoStream for Packets<VideoPacket> {}
+
+    fn no_keys(allow_unencrypted: bool) -> EncryptionMaterial {
+        EncryptionMaterial {
+            stream_connection_id: None,
+            chacha_key: None,
+            session_key: None,
+            aeskey: None,
+            aesiv: None,
+            aes_gcm: false,
+            allow_unencrypted,
+        }
+    }
+
+    fn codec_header(flags: u8) -> VideoHeader {
+        let mut header = [0u8; VideoHeader::LEN];
+        header[4] = 1;
+        header[5] = flags;
+        VideoHeader::parse(&header)
+    }
+
+    #[test]
+    fn codec_kind_from_record() {
+        // AVCDecoderConfigurationRecord of High profile, level 4.0
+        const AVCC: &[u8] = &[0x01, 0x64, 0x00, 0x28, 0xff, 0xe1, 0x00, 0x04];
+
+        // Keyframe flag doesn't make it HEVC
+        assert!(matches!(
+            video_packet_kind(&codec_header(0x10), AVCC),
+            PacketKind::AvcC
+        ));
+        assert!(matches!(
+            video_packet_kind(&codec_header(0), b"\x00\x00\x00\x20hvc1"),
+            PacketKind::HvcC
+        ));
+    }
+
+    #[test]
+    fn unencrypted_only_if_allowed() {
+        assert!(matches!(
+            Encryption::try_from(no_keys(true)),
+            Ok(Encryption::None)
+        ));
+        assert!(matches!(
+            Encryption::try_from(no_keys(false)),
+            Err(err) if err.kind() == io::ErrorKind::InvalidInput
+        ));
+
+        // Session key alone isn't enough, stream must be identified too
+        let material = EncryptionMaterial {
+            session_key: Some(SessionKey {
+                key_material: vec![0x42; 32].into(),
+                upgrade_channel: true,
+                pairing_id: None,
+            }),
+            ..no_keys(false)
+        };
+        assert!(Encryption::try_from(material).is_err());
+    }
+
+    #[test]
+    fn keys_win_over_unencrypted() {
+        let material = EncryptionMaterial {
+            chacha_key: Some(Secret::new([1; 32])),
+            ..no_keys(true)
+        };
+        assert!(matches!(
+            Encryption::try_from(material),
+            Ok(Encryption::ChaCha { .. })
+        ));
+
+        let material = EncryptionMaterial {
+            aeskey: Some(Secret::new([1; 16])),
+            aesiv: Some([2; 16]),
+            ..no_keys(true)
+        };
+        assert!(matches!(
+            Encryption::try_from(material),
+            Ok(Encryption::Legacy { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_audio_is_dropped() {
+        const KEY: [u8; 32] = [7; 32];
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let stream = Packets::<AudioPacket>(Mutex::default());
+
+        // RTP header with sequence number 1, garbage instead of payload, tag and nonce
+        let mut forged = vec![0x80, 0x60, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+        forged.extend_from_slice(&[0xaa; 40]);
+        sender.send_to(&forged, addr).await.unwrap();
+
+        let mut genuine = BytesMut::from(&[0x80, 0x60, 0, 2, 0, 0, 0, 2, 0, 0, 0, 3][..]);
+        let mut payload = BytesMut::from(&b"audio frame"[..]);
+        ChaCha20Poly1305::new(&Key::from(KEY))
+            .encrypt_in_place(&Nonce::from([0; 12]), &genuine[4..12], &mut payload)
+            .unwrap();
+        genuine.unsplit(payload);
+        genuine.extend_from_slice(&[0; 8]);
+        sender.send_to(&genuine, addr).await.unwrap();
+
+        let processor = audio_realtime_processor(
+            addr.ip(),
+            socket,
+            &stream,
+            1024,
+            Encryption::ChaCha {
+                key: Secret::new(KEY),
+            },
+        );
+        tokio::select! {
+            res = processor => panic!("processor stopped: {res:?}"),
+            () = async {
+                while stream.0.lock().unwrap().is_empty() {
+                    tokio::task::yield_now().await;
+                }
+            } => {}
+        }
+
+        let packets = stream.0.lock().unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(&packets[0].rtp[12..], b"audio frame");
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_video_is_dropped() {
+        const AVCC: &[u8] = &[0x01, 0x64, 0x00, 0x28, 0xff, 0xe1, 0x00, 0x04];
+
+        let (mut writer, reader) = tokio::io::duplex(1024);
+        let stream = Packets::<VideoPacket>(Mutex::default());
+
+        // Payload packet with garbage instead of ciphertext and tag
+        let mut forged = [0u8; VideoHeader::LEN];
+        forged[..4].copy_from_slice(&32u32.to_le_bytes());
+        writer.write_all(&forged).await.unwrap();
+        writer.write_all(&[0xaa; 32]).await.unwrap();
+
+        // Codec packets are sent in plain
+        let mut codec = [0u8; VideoHeader::LEN];
+        codec[..4].copy_from_slice(&(AVCC.len() as u32).to_le_bytes());
+        codec[4] = 1;
+        writer.write_all(&codec).await.unwrap();
+        writer.write_all(AVCC).await.unwrap();
+        drop(writer);
+
+        let encryption = Encryption::AesGcm {
+            key: Secret::new([0x11; 16]),
+            iv: [0; 16],
+            stream_connection_id: None,
+        };
+        let res = video_processor(reader, &stream, 1024, encryption).await;
+        assert!(matches!(res, Err(err) if err.kind() == io::ErrorKind::UnexpectedEof));
+
+        let packets = stream.0.lock().unwrap();
+        assert_eq!(packets.len(), 1);
+        assert!(matches!(packets[0].kind, PacketKind::AvcC));
+        assert_eq!(&packets[0].payload[..], AVCC);
     }
 }
@@ -0,0 +1,112 @@
+/// Sliding window of accepted packet counters, as in RFC 4303 (ESP).
+///
+/// Counters newer than the window move it forward, older ones are only accepted once and while
+/// they are inside the window, so reordered packets pass and replayed ones don't.
+#[derive(Debug, Default)]
+pub struct ReplayWindow {
+    /// Highest accepted counter
+    top: Option<u64>,
+    /// Bit `n` is set if `top - n` is accepted
+    bitmap: u128,
+}
+
+impl ReplayWindow {
+    pub const SIZE: u64 = u128::BITS as u64;
+
+    /// Whether the counter may be accepted, the window isn't changed.
+    pub fn check(&self, counter: u64) -> bool {
+        match self.top {
+            Some(top) if counter <= top => {
+                let offset = top - counter;
+                offset < Self::SIZE && self.bitmap & (1 << offset) == 0
+            }
+            _ => true,
+        }
+    }
+
+    /// Marks the counter as accepted, must be called once the packet is authenticated.
+    pub fn update(&mut self, counter: u64) {
+        match self.top {
+            Some(top) if counter <= top => {
+                let offset = top - counter;
+                if offset < Self::SIZE {
+                    self.bitmap |= 1 << offset;
+                }
+            }
+            top => {
+                let shift = top.map_or(Self::SIZE, |top| counter - top);
+                self.bitmap = if shift < Self::SIZE {
+                    self.bitmap << shift
+                } else {
+                    0
+                };
+                self.bitmap |= 1;
+                self.top = Some(counter);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReplayWindow;
+
+    fn accept(window: &mut ReplayWindow, counter: u64) -> bool {
+        let accepted = window.check(counter);
+        if accepted {
+            window.update(counter);
+        }
+        accepted
+    }
+
+    #[test]
+    fn in_order() {
+        let mut window = ReplayWindow::default();
+
+        assert!((0..1000).all(|counter| accept(&mut window, counter)));
+        assert!(!accept(&mut window, 999));
+        assert!(!accept(&mut window, 0));
+    }
+
+    #[test]
+    fn reordered() {
+        let mut window = ReplayWindow::default();
+
+        assert!(accept(&mut window, 10));
+        assert!(accept(&mut window, 12));
+        assert!(accept(&mut window, 11));
+        assert!(accept(&mut window, 5));
+        assert!(!accept(&mut window, 11));
+        assert!(!accept(&mut window, 5));
+    }
+
+    #[test]
+    fn too_old() {
+        let mut window = ReplayWindow::default();
+
+        assert!(accept(&mut window, 1000));
+        assert!(accept(&mut window, 1000 - ReplayWindow::SIZE + 1));
+        assert!(!accept(&mut window, 1000 - ReplayWindow::SIZE));
+    }
+
+    #[test]
+    fn large_jump() {
+        let mut window = ReplayWindow::default();
+
+        assert!(accept(&mut window, 1));
+        assert!(accept(&mut window, u64::MAX));
+        assert!(!accept(&mut window, u64::MAX));
+        assert!(!accept(&mut window, 1));
+        assert!(accept(&mut window, u64::MAX - 1));
+    }
+
+    #[test]
+    fn check_keeps_window() {
+        let mut window = ReplayWindow::default();
+
+        assert!(accept(&mut window, 7));
+        // Checked, but not authenticated, so the window stays
+        assert!(window.check(7 + ReplayWindow::SIZE * 2));
+        assert!(accept(&mut window, 6));
+    }
+}
@@ -0,0 +1,58 @@
+use std::{collections::BTreeMap, fs, io, net::SocketAddr, path::Path};
+
+/// Keys of an RTSP connection, streams' keys are taken from their `SETUP` requests.
+#[derive(Debug, Default)]
+pub struct ConnectionKeys {
+    pub session_key: Option<Vec<u8>>,
+    pub aes_key: Option<[u8; 16]>,
+    pub aes_iv: Option<[u8; 16]>,
+    pub aes_gcm: bool,
+}
+
+/// Connections are keyed by receiver's and sender's addresses, the latest key wins.
+pub fn read(path: &Path) -> io::Result<BTreeMap<(SocketAddr, SocketAddr), ConnectionKeys>> {
+    let mut connections = BTreeMap::<_, ConnectionKeys>::new();
+    for (n, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let mut fields = line.split_whitespace();
+        let (Some(label), Some(Ok(local_addr)), Some(Ok(remote_addr))) = (
+            fields.next(),
+            fields.next().map(str::parse),
+            fields.next().map(str::parse),
+        ) else {
+            tracing::warn!(line = n + 1, "malformed key log line");
+            continue;
+        };
+
+        let keys = connections.entry((local_addr, remote_addr)).or_default();
+        let parsed = match label {
+            "SESSION_KEY" => fields
+                .next()
+                .and_then(|key| hex::decode(key).ok())
+                .map(|key| keys.session_key = Some(key)),
+            "AES_KEY" => fields
+                .next()
+                .and_then(|key| hex::decode(key).ok())
+                .zip(fields.next().and_then(|iv| hex::decode(iv).ok()))
+                .and_then(|(key, iv)| Some((key.try_into().ok()?, iv.try_into().ok()?)))
+                .zip(match fields.next() {
+                    // Logs of older versions have no mode
+                    None | Some("cbc") => Some(false),
+                    Some("gcm") => Some(true),
+                    Some(_) => None,
+                })
+                .map(|((key, iv), gcm)| {
+                    keys.aes_key = Some(key);
+                    keys.aes_iv = Some(iv);
+                    keys.aes_gcm = gcm;
+                }),
+            // Also present in SETUP requests
+            "STREAM" => Some(()),
+            _ => None,
+        };
+        if parsed.is_none() {
+            tracing::warn!(line = n + 1, %label, "malformed key log entry");
+        }
+    }
+
+    Ok(connections)
+}
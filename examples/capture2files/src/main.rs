@@ -0,0 +1,135 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use airplay::{
+    capture::{Direction, StreamKeys},
+    config::Secret,
+};
+use tracing::level_filters::LevelFilter;
+
+mod keylog;
+mod media;
+mod pcap;
+mod rtsp;
+
+const USAGE: &str = "usage: rairplay-capture2files <capture.pcap[ng]> <keylog> <output dir>";
+
+fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_max_level(LevelFilter::INFO)
+        .init();
+
+    let args = env::args_os()
+        .skip(1)
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+    let [capture, keylog, output] = args.as_slice() else {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    };
+
+    match run(capture, keylog, output) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            tracing::error!(%err, "capture isn't decrypted");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(capture: &Path, keylog: &Path, output: &Path) -> io::Result<()> {
+    let connections = keylog::read(keylog)?;
+    let mut capture = pcap::Capture::read(capture)?;
+    fs::create_dir_all(output)?;
+
+    for (n, ((local_addr, remote_addr), keys)) in connections.into_iter().enumerate() {
+        let Some(requests) = capture.take_tcp(remote_addr, local_addr) else {
+            tracing::warn!(%local_addr, %remote_addr, "connection isn't captured");
+            continue;
+        };
+        let responses = capture
+            .take_tcp(local_addr, remote_addr)
+            .unwrap_or_default();
+        tracing::info!(%local_addr, %remote_addr, "connection found");
+
+        let session_key = keys.session_key.as_deref();
+        let (requests, frames) = rtsp::messages(requests, session_key, Direction::FromSender);
+        let (responses, _) = rtsp::messages(responses, session_key, Direction::FromReceiver);
+
+        let stem = output.join(format!("session-{n}"));
+        let mut log = BufWriter::new(File::create(stem.with_extension("txt"))?);
+        let mut setups = Vec::new();
+        for (i, request) in requests.iter().enumerate() {
+            request.write(&mut log, ">")?;
+            let Some(response) = responses.get(i) else {
+                continue;
+            };
+            response.write(&mut log, "<")?;
+
+            if request.start_line().starts_with("SETUP ") {
+                setups.extend(rtsp::stream_setups(request, response));
+            }
+        }
+        log.flush()?;
+
+        if !frames.is_empty() {
+            let stream_keys = StreamKeys {
+                session_key: None,
+                aes_key: keys.aes_key.map(Secret::new),
+                aes_iv: keys.aes_iv,
+                aes_gcm: keys.aes_gcm,
+                shared_key: None,
+                stream_connection_id: None,
+            };
+            let stem = output.join(format!("session-{n}-legacy"));
+            media::video(vec![frames], local_addr, stream_keys, &stem)?;
+        }
+
+        for (i, setup) in setups.into_iter().enumerate() {
+            let to = SocketAddr::new(local_addr.ip(), setup.data_port);
+            let stream_keys = StreamKeys {
+                session_key: keys.session_key.clone().map(Secret::new),
+                aes_key: keys.aes_key.map(Secret::new),
+                aes_iv: keys.aes_iv,
+                aes_gcm: keys.aes_gcm,
+                shared_key: setup.shared_key.map(Secret::new),
+                stream_connection_id: setup.stream_connection_id,
+            };
+            let stem = output.join(format!("session-{n}-stream-{i}"));
+
+            match setup.stream_type {
+                rtsp::STREAM_AUDIO_REALTIME => media::realtime_audio(
+                    &capture,
+                    remote_addr.ip(),
+                    to,
+                    stream_keys,
+                    setup.audio_format,
+                    &stem,
+                )?,
+                rtsp::STREAM_AUDIO_BUFFERED => media::buffered_audio(
+                    &mut capture,
+                    remote_addr.ip(),
+                    to,
+                    stream_keys,
+                    setup.audio_format,
+                    &stem,
+                )?,
+                rtsp::STREAM_VIDEO => media::video(
+                    capture.take_tcp_to(remote_addr.ip(), to),
+                    to,
+                    stream_keys,
+                    &stem,
+                )?,
+                other => tracing::warn!(stream_type = other, "unknown stream type"),
+            }
+        }
+    }
+
+    Ok(())
+}
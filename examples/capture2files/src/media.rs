@@ -0,0 +1,231 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Cursor, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+
+use airplay::{
+    capture::{AudioDecryptor, BufferedAudioDecoder, StreamKeys, VideoDecoder},
+    playback::{
+        annexb::{AnnexBConverter, VideoCodec},
+        audio::{AUDIO_FORMATS, AudioPacket, Codec, CodecKind},
+        video::PacketKind,
+    },
+};
+use bytes::BytesMut;
+use plist::Value;
+use tokio_util::codec::Decoder;
+
+use crate::pcap::Capture;
+
+/// Payloads of realtime audio's datagrams one after another.
+pub fn realtime_audio(
+    capture: &Capture,
+    from: IpAddr,
+    to: SocketAddr,
+    keys: StreamKeys,
+    format: Option<usize>,
+    stem: &Path,
+) -> io::Result<()> {
+    let mut decryptor = AudioDecryptor::new(keys);
+    let mut out = AudioOutput::create(format, stem)?;
+
+    let (mut packets, mut failed) = (0usize, 0usize);
+    for datagram in capture.udp_to(from, to) {
+        let mut rtp = datagram.clone();
+        match decryptor.decrypt(&mut rtp) {
+            Ok(()) => {
+                out.write_frame(&rtp[AudioPacket::HEADER_LEN..])?;
+                packets += 1;
+            }
+            Err(_) => failed += 1,
+        }
+    }
+    tracing::info!(%to, %packets, %failed, "realtime audio decrypted");
+
+    out.flush()
+}
+
+/// Payloads of buffered audio's packets one after another.
+pub fn buffered_audio(
+    capture: &mut Capture,
+    from: IpAddr,
+    to: SocketAddr,
+    keys: StreamKeys,
+    format: Option<usize>,
+    stem: &Path,
+) -> io::Result<()> {
+    let mut out = AudioOutput::create(format, stem)?;
+
+    for mut data in capture.take_tcp_to(from, to) {
+        let mut decoder = BufferedAudioDecoder::new(keys.clone());
+        let mut packets = 0usize;
+        while let Some(packet) = decoder.decode(&mut data).unwrap_or_else(|err| {
+            tracing::warn!(%err, %to, "buffered audio is cut");
+            None
+        }) {
+            out.write_frame(&packet.rtp[AudioPacket::HEADER_LEN..])?;
+            packets += 1;
+        }
+        tracing::info!(%to, %packets, left = data.len(), "buffered audio decrypted");
+    }
+
+    out.flush()
+}
+
+/// Annex-B stream of the mirroring connections, its plists are written next to it as XML.
+pub fn video(
+    connections: Vec<BytesMut>,
+    to: SocketAddr,
+    keys: StreamKeys,
+    stem: &Path,
+) -> io::Result<()> {
+    // Extension is known after the first codec packet
+    let mut frames = None;
+    let mut converter = AnnexBConverter::new();
+    let mut events = BufWriter::new(File::create(stem.with_extension("plist.txt"))?);
+
+    for mut data in connections {
+        let mut decoder = VideoDecoder::new(keys.clone());
+        let (mut packets, mut failed) = (0usize, 0usize);
+        while let Some(packet) = decoder.decode(&mut data).unwrap_or_else(|err| {
+            tracing::warn!(%err, %to, "video is cut");
+            None
+        }) {
+            packets += 1;
+            if matches!(packet.kind, PacketKind::Plist) {
+                match Value::from_reader(Cursor::new(&packet.payload[..])) {
+                    Ok(plist) => {
+                        plist.to_writer_xml(&mut events).map_err(io::Error::other)?;
+                        writeln!(events, "\n")?;
+                    }
+                    Err(err) => tracing::warn!(%err, "malformed plist packet"),
+                }
+                continue;
+            }
+
+            match converter.push(packet) {
+                Ok(Some(frame)) => {
+                    if frames.is_none() {
+                        let path = stem.with_extension(match frame.codec {
+                            VideoCodec::H264 => "h264",
+                            VideoCodec::H265 => "h265",
+                        });
+                        frames = Some(BufWriter::new(File::create(path)?));
+                    }
+                    if let Some(frames) = &mut frames {
+                        frames.write_all(&frame.data)?;
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    tracing::debug!(%err, "frame isn't converted");
+                    failed += 1;
+                }
+            }
+        }
+        tracing::info!(%to, %packets, %failed, left = data.len(), "video decrypted");
+    }
+
+    if let Some(mut frames) = frames {
+        frames.flush()?;
+    }
+    events.flush()
+}
+
+/// Frames one after another. AAC-LC gets ADTS headers and can be played as is, the codec of
+/// other formats is written next to them, so a decoder can be set up by hand.
+struct AudioOutput {
+    out: BufWriter<File>,
+    adts: Option<Adts>,
+}
+
+impl AudioOutput {
+    fn create(format: Option<usize>, stem: &Path) -> io::Result<Self> {
+        let codec = format.and_then(|index| AUDIO_FORMATS.get(index)).copied();
+        let adts = format
+            .zip(codec)
+            .and_then(|(index, codec)| Adts::new(index, codec));
+        if adts.is_none()
+            && let Some((index, codec)) = format.zip(codec)
+        {
+            fs::write(
+                stem.with_extension("codec.txt"),
+                format!("audioFormatIndex {index}: {codec:?}\n"),
+            )?;
+        }
+
+        Ok(Self {
+            out: BufWriter::new(File::create(stem.with_extension(extension(codec)))?),
+            adts,
+        })
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        if let Some(adts) = &self.adts {
+            self.out.write_all(&adts.header(frame.len()))?;
+        }
+        self.out.write_all(frame)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Fixed part of ADTS header, only AAC-LC may be put there.
+struct Adts {
+    freq_index: u8,
+    channels: u8,
+}
+
+impl Adts {
+    const LEN: usize = 7;
+    /// Audio object type 2 minus one
+    const PROFILE_LC: u8 = 1;
+
+    fn new(format: usize, codec: Codec) -> Option<Self> {
+        // AAC-LC/44100/2 and AAC-LC/48000/2, other AAC formats are ELD
+        if !matches!(format, 22 | 23) {
+            return None;
+        }
+        let freq_index = match codec.sample_rate {
+            48000 => 3,
+            44100 => 4,
+            _ => return None,
+        };
+
+        Some(Self {
+            freq_index,
+            channels: codec.channels,
+        })
+    }
+
+    fn header(&self, payload_len: usize) -> [u8; Self::LEN] {
+        // 13 bits, header included
+        let len = payload_len + Self::LEN;
+
+        [
+            0xff,
+            // MPEG-4, no CRC
+            0xf1,
+            (Self::PROFILE_LC << 6) | (self.freq_index << 2) | (self.channels >> 2),
+            ((self.channels & 0b11) << 6) | ((len >> 11) & 0b11) as u8,
+            (len >> 3) as u8,
+            ((len & 0b111) << 5) as u8 | 0x1f,
+            // Buffer fullness is VBR, a single raw data block
+            0xfc,
+        ]
+    }
+}
+
+fn extension(codec: Option<Codec>) -> &'static str {
+    match codec.map(|codec| codec.kind) {
+        Some(CodecKind::Pcm) => "pcm",
+        Some(CodecKind::Aac) => "aac",
+        Some(CodecKind::Opus) => "opus",
+        Some(CodecKind::Alac) => "alac",
+        None => "bin",
+    }
+}
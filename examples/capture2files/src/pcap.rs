@@ -0,0 +1,193 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+
+use bytes::BytesMut;
+use etherparse::{NetSlice, SlicedPacket, TransportSlice};
+use pcap_file::{
+    DataLink,
+    pcap::PcapReader,
+    pcapng::{Block, PcapNgReader},
+};
+
+const PCAPNG_MAGIC: &[u8] = &[0x0A, 0x0D, 0x0D, 0x0A];
+
+/// Flows are keyed by source and destination addresses.
+#[derive(Default)]
+pub struct Capture {
+    tcp: HashMap<(SocketAddr, SocketAddr), TcpFlow>,
+    udp: HashMap<(SocketAddr, SocketAddr), Vec<BytesMut>>,
+}
+
+#[derive(Default)]
+struct TcpFlow {
+    next_seq: Option<u32>,
+    out_of_order: Vec<(u32, Vec<u8>)>,
+    data: BytesMut,
+}
+
+impl Capture {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let file = fs::read(path)?;
+        let mut capture = Self::default();
+
+        if file.starts_with(PCAPNG_MAGIC) {
+            let mut reader = PcapNgReader::new(&file[..]).map_err(io::Error::other)?;
+            let mut datalinks = Vec::new();
+            while let Some(block) = reader.next_block() {
+                match block.map_err(io::Error::other)? {
+                    Block::InterfaceDescription(idb) => datalinks.push(idb.linktype),
+                    Block::EnhancedPacket(epb) => {
+                        if let Some(&datalink) = datalinks.get(epb.interface_id as usize) {
+                            capture.push(datalink, &epb.data);
+                        }
+                    }
+                    Block::SimplePacket(spb) => {
+                        if let Some(&datalink) = datalinks.first() {
+                            capture.push(datalink, &spb.data);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        } else {
+            let mut reader = PcapReader::new(&file[..]).map_err(io::Error::other)?;
+            let datalink = reader.header().datalink;
+            while let Some(packet) = reader.next_packet() {
+                capture.push(datalink, &packet.map_err(io::Error::other)?.data);
+            }
+        }
+
+        for ((src, dst), flow) in &capture.tcp {
+            if !flow.out_of_order.is_empty() {
+                tracing::warn!(
+                    %src,
+                    %dst,
+                    segments = flow.out_of_order.len(),
+                    "flow has gaps, data after them is dropped"
+                );
+            }
+        }
+
+        Ok(capture)
+    }
+
+    /// Takes reassembled payload of a TCP connection's direction.
+    pub fn take_tcp(&mut self, src: SocketAddr, dst: SocketAddr) -> Option<BytesMut> {
+        self.tcp.remove(&(src, dst)).map(|flow| flow.data)
+    }
+
+    /// Takes reassembled payloads of all TCP connections from the host to the address.
+    pub fn take_tcp_to(&mut self, src: IpAddr, dst: SocketAddr) -> Vec<BytesMut> {
+        let keys = self
+            .tcp
+            .keys()
+            .filter(|(s, d)| s.ip() == src && *d == dst)
+            .copied()
+            .collect::<Vec<_>>();
+
+        keys.into_iter()
+            .filter_map(|(s, d)| self.take_tcp(s, d))
+            .collect()
+    }
+
+    /// Datagrams from the host to the address in order of capture.
+    pub fn udp_to(&self, src: IpAddr, dst: SocketAddr) -> impl Iterator<Item = &BytesMut> {
+        self.udp
+            .iter()
+            .filter(move |((s, d), _)| s.ip() == src && *d == dst)
+            .flat_map(|(_, datagrams)| datagrams)
+    }
+
+    fn push(&mut self, datalink: DataLink, data: &[u8]) {
+        let sliced = match datalink {
+            DataLink::ETHERNET => SlicedPacket::from_ethernet(data),
+            DataLink::LINUX_SLL => SlicedPacket::from_linux_sll(data),
+            DataLink::RAW | DataLink::IPV4 | DataLink::IPV6 => SlicedPacket::from_ip(data),
+            // BSD loopback, 4 bytes of address family precede IP
+            DataLink::NULL if data.len() > 4 => SlicedPacket::from_ip(&data[4..]),
+            other => {
+                tracing::trace!(?other, "unsupported link type");
+                return;
+            }
+        };
+        let Ok(sliced) = sliced else {
+            return;
+        };
+
+        let (src_ip, dst_ip) = match &sliced.net {
+            Some(NetSlice::Ipv4(ip)) => (
+                IpAddr::V4(ip.header().source_addr()),
+                IpAddr::V4(ip.header().destination_addr()),
+            ),
+            Some(NetSlice::Ipv6(ip)) => (
+                IpAddr::V6(ip.header().source_addr()),
+                IpAddr::V6(ip.header().destination_addr()),
+            ),
+            _ => return,
+        };
+
+        match &sliced.transport {
+            Some(TransportSlice::Tcp(tcp)) => {
+                let src = SocketAddr::new(src_ip, tcp.source_port());
+                let dst = SocketAddr::new(dst_ip, tcp.destination_port());
+                self.tcp.entry((src, dst)).or_default().push(
+                    tcp.sequence_number(),
+                    tcp.syn(),
+                    tcp.payload(),
+                );
+            }
+            Some(TransportSlice::Udp(udp)) => {
+                let src = SocketAddr::new(src_ip, udp.source_port());
+                let dst = SocketAddr::new(dst_ip, udp.destination_port());
+                self.udp
+                    .entry((src, dst))
+                    .or_default()
+                    .push(BytesMut::from(udp.payload()));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl TcpFlow {
+    fn push(&mut self, seq: u32, syn: bool, payload: &[u8]) {
+        if syn {
+            self.next_seq = Some(seq.wrapping_add(1));
+            return;
+        }
+        if payload.is_empty() {
+            return;
+        }
+
+        // Capture may start in the middle of the connection
+        let next_seq = *self.next_seq.get_or_insert(seq);
+        if (seq.wrapping_sub(next_seq) as i32) > 0 {
+            self.out_of_order.push((seq, payload.to_vec()));
+            return;
+        }
+
+        self.append(seq, payload);
+        while let Some(pos) = self
+            .out_of_order
+            .iter()
+            .position(|(seq, _)| (seq.wrapping_sub(self.next_seq.unwrap_or(*seq)) as i32) <= 0)
+        {
+            let (seq, payload) = self.out_of_order.swap_remove(pos);
+            self.append(seq, &payload);
+        }
+    }
+
+    fn append(&mut self, seq: u32, payload: &[u8]) {
+        let next_seq = self.next_seq.unwrap_or(seq);
+        // Retransmitted bytes are skipped
+        let known = next_seq.wrapping_sub(seq) as usize;
+        if let Some(new) = payload.get(known..) {
+            self.data.extend_from_slice(new);
+            self.next_seq = Some(next_seq.wrapping_add(new.len() as u32));
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use std::io::{self, Cursor, Write};
+
+use airplay::capture::{ControlDecoder, Direction};
+use bytes::{Bytes, BytesMut};
+use plist::Value;
+use tokio_util::codec::Decoder;
+
+const HEAD_END: &[u8] = b"\r\n\r\n";
+
+pub const STREAM_AUDIO_REALTIME: u64 = 96;
+pub const STREAM_AUDIO_BUFFERED: u64 = 103;
+pub const STREAM_VIDEO: u64 = 110;
+/// AirPlay 1 mirroring, frames follow this request on the same connection
+pub const LEGACY_STREAM: &str = "POST /stream ";
+
+#[derive(Debug)]
+pub struct Message {
+    pub head: String,
+    pub body: Bytes,
+}
+
+#[derive(Debug)]
+pub struct StreamSetup {
+    pub stream_type: u64,
+    pub data_port: u16,
+    pub stream_connection_id: Option<u64>,
+    pub shared_key: Option<[u8; 32]>,
+    /// Index into `AUDIO_FORMATS`
+    pub audio_format: Option<usize>,
+}
+
+impl Message {
+    pub fn start_line(&self) -> &str {
+        self.head.lines().next().unwrap_or_default()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.head.lines().skip(1).find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim()
+                .eq_ignore_ascii_case(name)
+                .then_some(value.trim())
+        })
+    }
+
+    pub fn plist(&self) -> Option<Value> {
+        if !self.header("Content-Type")?.contains("plist") {
+            return None;
+        }
+
+        Value::from_reader(Cursor::new(&self.body)).ok()
+    }
+
+    pub fn write(&self, out: &mut impl Write, prefix: &str) -> io::Result<()> {
+        for line in self.head.lines() {
+            writeln!(out, "{prefix} {line}")?;
+        }
+        writeln!(out)?;
+
+        if self.body.is_empty() {
+            return Ok(());
+        }
+        if let Some(plist) = self.plist() {
+            plist.to_writer_xml(&mut *out).map_err(io::Error::other)?;
+            writeln!(out)?;
+        } else if let Ok(text) = str::from_utf8(&self.body) {
+            writeln!(out, "{text}")?;
+        } else {
+            writeln!(out, "<{} bytes of binary data>", self.body.len())?;
+            for chunk in self.body.chunks(16) {
+                let hex = chunk.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>();
+                writeln!(out, "{}", hex.join(" "))?;
+            }
+        }
+        writeln!(out)
+    }
+}
+
+/// Splits a direction of the RTSP connection into messages, which are encrypted after pairing.
+/// Data after [`LEGACY_STREAM`] request isn't parsed and is returned as is.
+pub fn messages(
+    mut data: BytesMut,
+    key_material: Option<&[u8]>,
+    direction: Direction,
+) -> (Vec<Message>, BytesMut) {
+    let mut key_material = key_material;
+    let mut messages = Vec::new();
+    loop {
+        // HAP block is 1024 bytes at most, so the second byte of its length is never a text
+        if data.get(1).is_some_and(|&byte| byte <= 4) {
+            let Some(key) = key_material.take() else {
+                tracing::warn!(?direction, "encrypted data without session key");
+                break;
+            };
+
+            let mut decoder = ControlDecoder::new(key, direction);
+            let mut plain = BytesMut::with_capacity(data.len());
+            loop {
+                match decoder.decode(&mut data) {
+                    Ok(Some(block)) => plain.extend_from_slice(&block),
+                    Ok(None) => break,
+                    Err(err) => {
+                        tracing::warn!(%err, ?direction, "HAP block isn't decrypted");
+                        break;
+                    }
+                }
+            }
+            data = plain;
+            continue;
+        }
+
+        match parse(&mut data) {
+            Some(message) if message.start_line().starts_with(LEGACY_STREAM) => {
+                messages.push(message);
+                return (messages, data);
+            }
+            Some(message) => messages.push(message),
+            None => break,
+        }
+    }
+
+    if !data.is_empty() {
+        tracing::warn!(
+            len = data.len(),
+            ?direction,
+            "incomplete message at the end"
+        );
+    }
+
+    (messages, BytesMut::new())
+}
+
+/// Streams set up by the exchange, they are matched by their order.
+pub fn stream_setups(request: &Message, response: &Message) -> Vec<StreamSetup> {
+    let streams = |message: &Message| {
+        message
+            .plist()
+            .and_then(Value::into_dictionary)
+            .and_then(|mut dict| dict.remove("streams"))
+            .and_then(Value::into_array)
+            .unwrap_or_default()
+    };
+
+    streams(request)
+        .iter()
+        .zip(streams(response).iter())
+        .filter_map(|(request, response)| {
+            let request = request.as_dictionary()?;
+            let response = response.as_dictionary()?;
+            let integer = |value: &Value| {
+                // Stream connection id is sent signed
+                value
+                    .as_unsigned_integer()
+                    .or_else(|| value.as_signed_integer().map(|x| x as u64))
+            };
+
+            Some(StreamSetup {
+                stream_type: request.get("type").and_then(integer)?,
+                data_port: response
+                    .get("dataPort")
+                    .and_then(integer)
+                    .and_then(|port| port.try_into().ok())?,
+                stream_connection_id: request.get("streamConnectionID").and_then(integer),
+                shared_key: request
+                    .get("shk")
+                    .and_then(Value::as_data)
+                    .and_then(|shk| shk.try_into().ok()),
+                audio_format: request
+                    .get("audioFormat")
+                    .and_then(integer)
+                    .and_then(|format| {
+                        let index = request
+                            .get("audioFormatIndex")
+                            .and_then(integer)
+                            .unwrap_or(u64::from(format.trailing_zeros()));
+                        usize::try_from(index).ok()
+                    }),
+            })
+        })
+        .collect()
+}
+
+fn parse(data: &mut BytesMut) -> Option<Message> {
+    let head_len = data.windows(HEAD_END.len()).position(|w| w == HEAD_END)?;
+    let mut message = Message {
+        head: String::from_utf8_lossy(&data[..head_len]).into_owned(),
+        body: Bytes::new(),
+    };
+    let body_len = message
+        .header("Content-Length")
+        .and_then(|len| len.parse::<usize>().ok())
+        .unwrap_or_default();
+
+    let start = head_len + HEAD_END.len();
+    if data.len() < start + body_len {
+        return None;
+    }
+
+    message.body = data.split_to(start + body_len).split_off(start).freeze();
+    Some(message)
+}
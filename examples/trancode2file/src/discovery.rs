@@ -1,18 +1,24 @@
-use airplay::config::Config;
+use airplay::config::{Config, Keychain, StatusFlags};
 use mdns_sd::{ServiceDaemon, ServiceInfo};
 
 const SERVICE_TYPE: &str = "_airplay._tcp.local.";
 const PROTOCOL_VERSION: &str = "1.1";
 
-pub fn mdns_broadcast<ADev, VDev, KC>(config: &Config<ADev, VDev, KC>) {
+pub fn mdns_broadcast<ADev, VDev, KC: Keychain>(config: &Config<ADev, VDev, KC>) {
     let mdns = ServiceDaemon::new().expect("Could not create service daemon");
 
     let instance_name = config.name.as_str();
     let service_hostname = format!("{}.local.", instance_name.replace(' ', "-"));
     let port = 5200;
 
-    let feature_txt = format_feature_bits(config.features.bits());
+    let feature_txt = format_feature_bits(config.advertised_features().bits());
     let device_id = config.mac_addr.to_string().to_uppercase();
+    let pairing_id = String::from_utf8_lossy(config.keychain.id());
+    let status_flags = config.status_flags();
+    let flags_txt = format!("0x{:X}", status_flags.bits());
+    let password_txt = status_flags
+        .contains(StatusFlags::PasswordRequired)
+        .to_string();
 
     let properties = [
         ("model", "AppleTV3,2"),
@@ -20,6 +26,9 @@ pub fn mdns_broadcast<ADev, VDev, KC>(config: &Config<ADev, VDev, KC>) {
         ("srcvers", "366.0"),
         ("features", feature_txt.as_str()),
         ("deviceid", device_id.as_str()),
+        ("flags", flags_txt.as_str()),
+        ("pw", password_txt.as_str()),
+        ("pi", pairing_id.as_ref()),
     ];
 
     let service_info = ServiceInfo::new(
@@ -34,13 +34,32 @@ async fn main() {
                 },
                 ..Default::default()
             },
-            pairing: airplay::config::Pairing::HomeKit,
+            pairing: airplay::config::Pairing::HomeKit {
+                mode: airplay::config::PairingMode::Both,
+            },
+            keychain: airplay::config::DefaultKeychain::random(rand::rng()),
+            // Like SSLKEYLOGFILE, to decrypt captures in Wireshark
+            keylog: std::env::var_os("AIRPLAY_KEYLOGFILE").map(|path| {
+                Arc::new(airplay::config::KeyLogFile::open(path).expect("key log file"))
+                    as Arc<dyn airplay::config::KeyLog>
+            }),
             ..Default::default()
         },
     );
 
     discovery::mdns_broadcast(config.as_ref());
 
+    // AirPlay 1 senders mirror the screen on a separate well-known port
+    tokio::spawn(airplay::rtsp::serve_legacy_mirroring(
+        tokio::net::TcpListener::bind(SocketAddrV4::new(
+            Ipv4Addr::UNSPECIFIED,
+            airplay::rtsp::LEGACY_MIRRORING_PORT,
+        ))
+        .await
+        .unwrap(),
+        Arc::clone(&config),
+    ));
+
     axum::serve(
         airplay::rtsp::Listener::bind(
             SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 5200),
@@ -9,7 +9,7 @@ use std::{
 use airplay::playback::{
     ChannelHandle, Device, Stream,
     audio::{AudioDevice, AudioPacket, AudioParams},
-    video::{VideoDevice, VideoPacket, VideoParams},
+    video::{MirroringEvent, VideoDevice, VideoPacket, VideoParams, VideoStream},
 };
 
 pub type PipeCallback<Params, Packet> =
@@ -73,6 +73,12 @@ impl AudioDevice for PipeDevice<AudioParams, AudioPacket> {
     fn set_volume(&self, _: f32) {}
 }
 
+impl VideoStream for PipeStream<VideoPacket> {
+    fn on_event(&self, event: MirroringEvent) {
+        tracing::info!(id = %self.id, ?event, "mirroring event");
+    }
+}
+
 pub struct PipeStream<T> {
     id: String,
     tx: mpsc::Sender<T>,
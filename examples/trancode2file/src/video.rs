@@ -9,9 +9,11 @@ use gstreamer_app::AppSrc;
 
 pub fn transcode(
     id: u64,
-    _params: VideoParams,
+    params: VideoParams,
     rx: mpsc::Receiver<VideoPacket>,
 ) -> Result<(), Box<dyn Error>> {
+    tracing::info!(%id, ?params, "video stream started");
+
     let mut ctx = None;
     loop {
         if let Ok(VideoPacket { kind, payload, .. }) = rx.recv() {
@@ -38,6 +40,7 @@ pub fn transcode(
                 PacketKind::Plist => {
                     tracing::debug!("plist packet received");
                 },
+                PacketKind::Heartbeat => {}
                 PacketKind::Other(kind) => {
                     tracing::debug!(%kind, "unknown packet type");
                 }